@@ -1,7 +1,10 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use std::collections::BinaryHeap;
 use rayon::prelude::*;
 use milkpq::MilkPQ;
 use rand::prelude::*;
-use criterion::{Criterion, criterion_group, criterion_main, BenchmarkId};
+use criterion::{Criterion, criterion_group, criterion_main, BenchmarkId, BatchSize};
 
 type MPQ = MilkPQ<i32>;
 
@@ -98,24 +101,56 @@ fn par_par_mix<I: ParallelIterator<Item = i32>>(mpq: &MPQ, iter: I, pops: usize)
 
 fn once_bench(c: &mut Criterion, mpq: &MPQ, name: &'static str) {
     c.bench_with_input(BenchmarkId::new("pop once", "Empty MilkPQ"), &MPQ::new(), |b, mpq| {
-        b.iter(|| once_pop(&mpq));
+        b.iter(|| once_pop(mpq));
     });
     c.bench_with_input(BenchmarkId::new("pop once", name), &mpq.clone(), |b, mpq| {
-        b.iter(|| once_pop(&mpq))
+        b.iter(|| once_pop(mpq))
     });
 
     c.bench_with_input(BenchmarkId::new("strong pop once", "Empty MilkPQ"), &MPQ::new(), |b, mpq| {
-        b.iter(|| once_strong_pop(&mpq));
+        b.iter(|| once_strong_pop(mpq));
     });
     c.bench_with_input(BenchmarkId::new("strong pop once", name), &mpq.clone(), |b, mpq| {
-        b.iter(|| once_strong_pop(&mpq))
+        b.iter(|| once_strong_pop(mpq))
     });
 
     c.bench_with_input(BenchmarkId::new("push once", "Empty MilkPQ"), &MPQ::new(), |b, mpq| {
-        b.iter(|| once_push(&mpq, 5000));
+        b.iter(|| once_push(mpq, 5000));
     });
     c.bench_with_input(BenchmarkId::new("push once", name), &mpq.clone(), |b, mpq| {
-        b.iter(|| once_push(&mpq, 5000))
+        b.iter(|| once_push(mpq, 5000))
+    });
+}
+
+#[inline(never)]
+fn push_not_inlined(mpq: &MPQ, t: i32) {
+    mpq.push(t);
+}
+
+#[inline(never)]
+fn pop_not_inlined(mpq: &MPQ) -> Option<i32> {
+    mpq.pop()
+}
+
+/// Benches link milkpq as a separate crate, so this is `push` and `pop` as
+/// users call them, next to the same calls through wrappers that can't be
+/// inlined. The gap between the two is what inlining across the crate
+/// boundary is worth.
+fn cross_crate_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Cross-crate push/pop");
+    let mpq = MPQ::new();
+
+    group.bench_with_input(BenchmarkId::new("Inlinable", "push then pop"), &mpq, |b, mpq| {
+        b.iter(|| {
+            mpq.push(5000);
+            mpq.pop()
+        })
+    });
+    group.bench_with_input(BenchmarkId::new("Not inlined", "push then pop"), &mpq, |b, mpq| {
+        b.iter(|| {
+            push_not_inlined(mpq, 5000);
+            pop_not_inlined(mpq)
+        })
     });
 }
 
@@ -173,92 +208,332 @@ fn strong_pop_bench(c: &mut Criterion, mpq: &MPQ, name: &'static str) {
     );
 }
 
-fn push_bench(c: &mut Criterion, mpq: &MPQ, insert: &Vec<i32>, name: &'static str) {
+fn push_bench(c: &mut Criterion, mpq: &MPQ, insert: &[i32], name: &'static str) {
     let mut group = c.benchmark_group("Push 10K elements");
 
     group.bench_with_input(
         BenchmarkId::new("Sequential", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_push(mpq, insert.iter().cloned()))
     );
     group.bench_with_input(
         BenchmarkId::new("Sequential", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_push(mpq, insert.iter().cloned()))
     );
 
     group.bench_with_input(
         BenchmarkId::new("Parallel", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| par_push(mpq, insert.par_iter().cloned()))
     );
     group.bench_with_input(
         BenchmarkId::new("Parallel", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| par_push(mpq, insert.par_iter().cloned()))
     );
 }
 
-fn mix_bench(c: &mut Criterion, mpq: &MPQ, insert: &Vec<i32>, name: &'static str) {
+fn mix_bench(c: &mut Criterion, mpq: &MPQ, insert: &[i32], name: &'static str) {
     let pops = insert.len();
     let mut group = c.benchmark_group("Mixed Pop/Push 10K elements");
 
     group.bench_with_input(
         BenchmarkId::new("SPSC single thead", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_mix(mpq, insert.iter().copied(), pops))
     );
     group.bench_with_input(
         BenchmarkId::new("SPSC single thead", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_mix(mpq, insert.iter().copied(), pops))
     );
 
     group.bench_with_input(
         BenchmarkId::new("SPSC split thread", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_seq_mix(mpq, insert.iter().copied(), pops))
     );
     group.bench_with_input(
         BenchmarkId::new("SPSC split thread", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_seq_mix(mpq, insert.iter().copied(), pops))
     );
 
     group.bench_with_input(
         BenchmarkId::new("MPSC", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| par_seq_mix(mpq, insert.par_iter().copied(), pops))
     );
     group.bench_with_input(
         BenchmarkId::new("MPSC", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| par_seq_mix(mpq, insert.par_iter().copied(), pops))
     );
 
     group.bench_with_input(
         BenchmarkId::new("SPMC", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_par_mix(mpq, insert.iter().copied(), pops))
     );
     group.bench_with_input(
         BenchmarkId::new("SPMC", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| seq_par_mix(mpq, insert.iter().copied(), pops))
     );
 
     group.bench_with_input(
         BenchmarkId::new("MPMC", "Empty MilkPQ"),
-        &(MPQ::new(), insert.clone()),
+        &(MPQ::new(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| par_par_mix(mpq, insert.par_iter().copied(), pops))
     );
     group.bench_with_input(
         BenchmarkId::new("MPMC", name),
-        &(mpq.clone(), insert.clone()),
+        &(mpq.clone(), insert.to_vec()),
         |b, (mpq, insert)| b.iter(|| par_par_mix(mpq, insert.par_iter().copied(), pops))
     );
 }
 
+fn extend_bench(c: &mut Criterion) {
+    let insert = (0..100_000).collect::<Vec<i32>>();
+
+    c.bench_with_input(BenchmarkId::new("extend_ref 100K", "Empty MilkPQ"), &insert, |b, insert| {
+        b.iter_batched(MPQ::new, |mpq| mpq.extend_ref(insert.iter().copied()), BatchSize::LargeInput)
+    });
+
+    let mut group = c.benchmark_group("Extend from 100K slice");
+    group.bench_with_input("extend_from_slice", &insert, |b, insert| {
+        b.iter_batched(MPQ::new, |mpq| mpq.extend_from_slice(insert), BatchSize::LargeInput)
+    });
+    group.bench_with_input("push each", &insert, |b, insert| {
+        b.iter_batched(MPQ::new, |mpq| for &t in insert {
+            mpq.push(t);
+        }, BatchSize::LargeInput)
+    });
+}
+
+fn from_iter_bench(c: &mut Criterion) {
+    let mut insert = (0..1_000_000).collect::<Vec<i32>>();
+    insert.shuffle(&mut thread_rng());
+    let mut group = c.benchmark_group("Build from 1M elements");
+    group.sample_size(10);
+
+    group.bench_with_input(BenchmarkId::new("FromIterator", "1M"), &insert, |b, insert| {
+        b.iter(|| insert.iter().copied().collect::<MPQ>())
+    });
+    group.bench_with_input(BenchmarkId::new("push", "1M"), &insert, |b, insert| {
+        b.iter(|| {
+            let mpq = MPQ::new();
+            seq_push(&mpq, insert.iter().copied());
+            mpq
+        })
+    });
+}
+
+fn home_push_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Uncontended push");
+
+    group.bench_function(BenchmarkId::new("Home subqueue", "push"), |b| {
+        b.iter_batched(MPQ::new, |mpq| once_push(&mpq, 0), BatchSize::SmallInput)
+    });
+    group.bench_function(BenchmarkId::new("Random subqueue", "push_to"), |b| {
+        b.iter_batched(MPQ::new, |mpq| {
+            let i = thread_rng().gen_range(0, mpq.num_queues());
+            mpq.push_to(i, 0);
+        }, BatchSize::SmallInput)
+    });
+}
+
+/// The alternative to `into_sorted_vec`'s single sort: sort each subqueue on
+/// its own and k-way merge the runs.
+fn merge_sorted_subqueues(mut mpq: MPQ) -> Vec<i32> {
+    let mut vec = Vec::with_capacity(mpq.len());
+    let mut runs = mpq
+        .heaps_mut()
+        .map(|mut heap| {
+            let mut run = std::mem::take(&mut *heap).into_vec();
+            run.sort_unstable();
+            run
+        })
+        .collect::<Vec<_>>();
+    let mut heads = runs
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, run)| Some((run.pop()?, i)))
+        .collect::<BinaryHeap<_>>();
+
+    while let Some((t, i)) = heads.pop() {
+        vec.push(t);
+
+        if let Some(next) = runs[i].pop() {
+            heads.push((next, i));
+        }
+    }
+
+    vec
+}
+
+fn single_queue_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Single subqueue");
+    let mpq = MPQ::with_queues(1);
+
+    group.bench_function("push then pop", |b| b.iter(|| {
+        mpq.push(0);
+        mpq.pop()
+    }));
+}
+
+fn sparse_strong_pop_bench(c: &mut Criterion) {
+    let mpq = MPQ::with_queues(64);
+
+    c.bench_function("strong_pop one of 64 subqueues", |b| b.iter(|| {
+        mpq.push_to(thread_rng().gen_range(0, 64), 0);
+        mpq.strong_pop()
+    }));
+}
+
+fn into_sorted_bench(c: &mut Criterion) {
+    let mut insert = (0..100_000).collect::<Vec<i32>>();
+    insert.shuffle(&mut thread_rng());
+    let mut group = c.benchmark_group("into_sorted_vec 100K");
+
+    for &limit in &[1, 4, 16, 64] {
+        let mpq = MPQ::with_queues(limit);
+        mpq.extend_ref(insert.iter().copied());
+
+        group.bench_with_input(BenchmarkId::new("Sort", limit), &mpq, |b, mpq| {
+            b.iter_batched(|| mpq.clone(), MPQ::into_sorted_vec, BatchSize::LargeInput)
+        });
+        group.bench_with_input(BenchmarkId::new("Merge", limit), &mpq, |b, mpq| {
+            b.iter_batched(|| mpq.clone(), merge_sorted_subqueues, BatchSize::LargeInput)
+        });
+    }
+}
+
+/// More threads than cores all pushing, popping and strong popping, which is
+/// where a spinning waiter keeps a preempted lock holder from running. Run it
+/// with and without the `mutex-backend` feature to compare the two.
+fn oversubscribed_bench(c: &mut Criterion) {
+    let backend = if cfg!(feature = "mutex-backend") { "Mutex" } else { "Spinlock" };
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut group = c.benchmark_group("Oversubscribed");
+
+    for &per_core in &[4, 16] {
+        let threads = cores * per_core;
+        let mpq = MPQ::with_queues(cores);
+
+        group.bench_with_input(BenchmarkId::new(backend, format!("{}x threads", per_core)), &mpq, |b, mpq| {
+            b.iter(|| std::thread::scope(|s| {
+                for _ in 0..threads {
+                    s.spawn(|| for i in 0..1000 {
+                        mpq.push(i);
+                        mpq.pop();
+                        mpq.push(i);
+                        mpq.strong_pop();
+                    });
+                }
+            }))
+        });
+    }
+}
+
+/// More threads than subqueues all pushing and popping, so lock attempts keep
+/// landing on a subqueue another thread holds and the retry loops get used.
+fn contended_bench(c: &mut Criterion) {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = cores * 4;
+    let mut group = c.benchmark_group("Contended");
+    let mpq = MPQ::with_queues(cores);
+
+    group.bench_with_input(BenchmarkId::new("push/pop", format!("{} threads", threads)), &mpq, |b, mpq| {
+        b.iter(|| std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| for i in 0..1000 {
+                    mpq.push(i);
+                    mpq.pop();
+                });
+            }
+        }))
+    });
+
+    mpq.extend_ref(0..threads as i32 * 1000);
+
+    group.bench_with_input(BenchmarkId::new("pop", format!("{} threads", threads)), &mpq, |b, mpq| {
+        b.iter_batched(|| mpq.clone(), |mpq| std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| for _ in 0..1000 {
+                    mpq.pop();
+                });
+            }
+        }), BatchSize::LargeInput)
+    });
+}
+
+fn cas_bench(c: &mut Criterion) {
+    let cas = if cfg!(feature = "strong-cas") { "compare_exchange" } else { "compare_exchange_weak" };
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()) * 2;
+    let mut group = c.benchmark_group("Lock CAS");
+    let mpq = MPQ::with_queues(1);
+
+    group.bench_with_input(BenchmarkId::new(cas, "1 thread"), &mpq, |b, mpq| {
+        b.iter(|| for i in 0..1000 {
+            mpq.push(i);
+            mpq.pop();
+        })
+    });
+
+    let mpq = MPQ::with_queues(threads / 2);
+
+    group.bench_with_input(BenchmarkId::new(cas, format!("{} threads", threads)), &mpq, |b, mpq| {
+        b.iter(|| std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| for i in 0..1000 {
+                    mpq.push(i);
+                    mpq.pop();
+                });
+            }
+        }))
+    });
+}
+
+fn clone_from_bench(c: &mut Criterion, mpq: &MPQ, name: &'static str) {
+    let mut group = c.benchmark_group("Clone into same-sized");
+    let mut target = mpq.clone();
+
+    group.bench_with_input(BenchmarkId::new("clone", name), mpq, |b, mpq| {
+        b.iter(|| target = mpq.clone())
+    });
+    group.bench_with_input(BenchmarkId::new("clone_from", name), mpq, |b, mpq| {
+        b.iter(|| target.clone_from(mpq))
+    });
+}
+
+#[cfg(feature = "rayon")]
+fn clone_bench(c: &mut Criterion, mpq: &MPQ, name: &'static str) {
+    let mut group = c.benchmark_group("Clone");
+
+    group.bench_with_input(BenchmarkId::new("Sequential", name), mpq, |b, mpq| {
+        b.iter(|| mpq.clone())
+    });
+    group.bench_with_input(BenchmarkId::new("Parallel", name), mpq, |b, mpq| {
+        b.iter(|| mpq.clone_par())
+    });
+}
+
+#[cfg(feature = "rayon")]
+fn drain_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Drain 1M");
+    let mpq = MPQ::with_queues(64);
+    mpq.extend_ref(0..1_000_000);
+
+    group.bench_with_input(BenchmarkId::new("Sequential", "64 subqueues"), &mpq, |b, mpq| {
+        b.iter_batched(|| mpq.clone(), |mut mpq| mpq.drain(), BatchSize::LargeInput)
+    });
+    group.bench_with_input(BenchmarkId::new("Parallel", "64 subqueues"), &mpq, |b, mpq| {
+        b.iter_batched(|| mpq.clone(), |mut mpq| mpq.drain_par(), BatchSize::LargeInput)
+    });
+}
+
 fn test(c: &mut Criterion) {
     let mut vs = (0..10000).collect::<Vec<_>>();
     vs.shuffle(&mut thread_rng());
@@ -267,10 +542,25 @@ fn test(c: &mut Criterion) {
     let name = "10K MilkPQ";
 
     once_bench(c, &mpq, name);
+    cross_crate_bench(c);
     pop_bench(c, &mpq, name);
     strong_pop_bench(c, &mpq, name);
     push_bench(c, &mpq, &vs, name);
     mix_bench(c, &mpq, &vs, name);
+    extend_bench(c);
+    from_iter_bench(c);
+    home_push_bench(c);
+    into_sorted_bench(c);
+    single_queue_bench(c);
+    sparse_strong_pop_bench(c);
+    oversubscribed_bench(c);
+    contended_bench(c);
+    cas_bench(c);
+    clone_from_bench(c, &mpq, name);
+    #[cfg(feature = "rayon")]
+    clone_bench(c, &mpq, name);
+    #[cfg(feature = "rayon")]
+    drain_bench(c);
 }
 
 criterion_group!(benches, test);
@@ -1,3 +1,5 @@
+#![allow(clippy::upper_case_acronyms)]
+
 use std::collections::BinaryHeap;
 use std::any::type_name;
 use rayon::prelude::*;
@@ -24,6 +26,7 @@ impl Clone for SPQ {
     }
 }
 
+#[allow(dead_code)]
 trait PQueue<T: Send + Sync>: Clone + Send + Sync {
     fn new_rand(n: usize) -> Self;
 
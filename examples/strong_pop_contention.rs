@@ -0,0 +1,32 @@
+use milkpq::MilkPQ;
+
+/// Many threads emptying one queue with `strong_pop`, reporting how many of
+/// their subqueue lock attempts failed along the way.
+fn main() {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()) * 16;
+    let runs = 20;
+    let mut attempts = 0;
+    let mut failures = 0;
+    let now = std::time::Instant::now();
+
+    for _ in 0..runs {
+        let mpq = MilkPQ::with_queues(8);
+        mpq.extend_ref(0..100_000);
+        mpq.reset_stats();
+
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| while mpq.strong_pop().is_some() {});
+            }
+        });
+
+        assert!(mpq.is_empty());
+        let stats = mpq.stats();
+        attempts += stats.lock_attempts;
+        failures += stats.lock_failures;
+    }
+
+    println!("{} threads, {} runs", threads, runs);
+    println!("{} lock attempts, {} failures per run", attempts / runs, failures / runs);
+    println!("{}ms", now.elapsed().as_millis());
+}
@@ -0,0 +1,119 @@
+//! The allocator behind each subqueue's heap.
+//!
+//! With the nightly-only `allocator_api` feature, [`MilkPQ`](crate::MilkPQ)
+//! is generic over std's `Allocator`, and every subqueue allocates its heap
+//! with a clone of the one it was made with. Without it, [`Allocator`] and
+//! [`Global`] are stand-ins for std's, so the same code builds on stable
+//! with the global allocator as the only choice.
+//!
+//! The helpers here are the only places a heap or buffer is made from
+//! scratch, since that's the part that differs between the two.
+
+use std::collections::{binary_heap, BinaryHeap};
+
+#[cfg(feature = "allocator_api")]
+pub use std::alloc::{Allocator, Global};
+
+#[cfg(feature = "allocator_api")]
+pub(crate) type Heap<T, A> = BinaryHeap<T, A>;
+
+#[cfg(feature = "allocator_api")]
+pub(crate) type Buf<T, A> = Vec<T, A>;
+
+#[cfg(feature = "allocator_api")]
+pub(crate) type HeapIntoIter<T, A> = binary_heap::IntoIter<T, A>;
+
+/// An empty heap with room for `cap` elements, allocated with `alloc`.
+#[cfg(feature = "allocator_api")]
+pub(crate) fn heap_in<T: Ord, A: Allocator>(cap: usize, alloc: A) -> Heap<T, A> {
+    BinaryHeap::with_capacity_in(cap, alloc)
+}
+
+/// An empty heap with room for `cap` elements, in the same allocator as
+/// `heap`.
+#[cfg(feature = "allocator_api")]
+pub(crate) fn heap_like<T: Ord, A: Allocator + Clone>(heap: &Heap<T, A>, cap: usize) -> Heap<T, A> {
+    heap_in(cap, heap.allocator().clone())
+}
+
+/// An empty buffer with room for `cap` elements, in the same allocator as
+/// `heap`, for heapifying into one of its siblings.
+#[cfg(feature = "allocator_api")]
+pub(crate) fn buf_like<T: Ord, A: Allocator + Clone>(heap: &Heap<T, A>, cap: usize) -> Buf<T, A> {
+    Vec::with_capacity_in(cap, heap.allocator().clone())
+}
+
+/// Moves the elements of `heap` into a [`Vec`] from the global allocator.
+///
+/// The heap's buffer could be in any allocator, so this always copies.
+#[cfg(feature = "allocator_api")]
+pub(crate) fn into_vec<T: Ord, A: Allocator>(heap: Heap<T, A>) -> Vec<T> {
+    heap.into_iter().collect()
+}
+
+/// Stand-in for `std::alloc::Allocator`, which only [`Global`] implements
+/// without the `allocator_api` feature.
+#[cfg(not(feature = "allocator_api"))]
+pub trait Allocator: sealed::Sealed {}
+
+/// Stand-in for `std::alloc::Global`, the global allocator.
+#[cfg(not(feature = "allocator_api"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+#[cfg(not(feature = "allocator_api"))]
+impl Allocator for Global {}
+
+#[cfg(not(feature = "allocator_api"))]
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::Global {}
+
+    /// `T` itself, but spelled with `A` in it, so the aliases below can take
+    /// the same parameters as with the feature even though they ignore `A`.
+    pub trait Ignoring<A> {
+        type Itself;
+    }
+
+    impl<T, A> Ignoring<A> for T {
+        type Itself = T;
+    }
+}
+
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) type Heap<T, A> = BinaryHeap<<T as sealed::Ignoring<A>>::Itself>;
+
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) type Buf<T, A> = Vec<<T as sealed::Ignoring<A>>::Itself>;
+
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) type HeapIntoIter<T, A> = binary_heap::IntoIter<<T as sealed::Ignoring<A>>::Itself>;
+
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) fn heap_in<T: Ord, A: Allocator>(cap: usize, _alloc: A) -> Heap<T, A> {
+    BinaryHeap::with_capacity(cap)
+}
+
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) fn heap_like<T: Ord, A: Allocator + Clone>(_heap: &Heap<T, A>, cap: usize) -> Heap<T, A> {
+    BinaryHeap::with_capacity(cap)
+}
+
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) fn buf_like<T: Ord, A: Allocator + Clone>(_heap: &Heap<T, A>, cap: usize) -> Buf<T, A> {
+    Vec::with_capacity(cap)
+}
+
+/// Reuses the heap's buffer, since it's already in the global allocator.
+#[cfg(not(feature = "allocator_api"))]
+pub(crate) fn into_vec<T: Ord, A: Allocator>(heap: Heap<T, A>) -> Vec<T> {
+    heap.into_vec()
+}
+
+/// Takes the elements of `heap`, leaving it empty with no capacity in the
+/// same allocator, which doesn't allocate.
+pub(crate) fn take<T: Ord, A: Allocator + Clone>(heap: &mut Heap<T, A>) -> Heap<T, A> {
+    let empty = heap_like::<T, A>(heap, 0);
+    std::mem::replace(heap, empty)
+}
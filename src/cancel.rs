@@ -0,0 +1,325 @@
+//! Cancelling queued elements by id.
+
+use std::collections::HashSet;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use super::{MilkPQ, order};
+use super::keyed::KeyOnly;
+
+/// How many more tombstones than twice the queue's length [`cancel()`]
+/// lets pile up before it drops the stale ones.
+///
+/// [`cancel()`]: CancellableMilkPQ::cancel()
+const STALE_TOMBSTONE_SLACK: usize = 64;
+
+/// A [`MilkPQ`] whose elements can be cancelled after being pushed.
+///
+/// Every push hands back an id, and [`cancel()`](Self::cancel()) marks that
+/// id with a tombstone instead of searching the subqueues for it. Cancelled
+/// elements stay queued until a pop reaches them, at which point they're
+/// discarded and popping carries on, so they're never returned.
+#[derive(Debug)]
+pub struct CancellableMilkPQ<T: Ord> {
+    pq: MilkPQ<KeyOnly<T, u64>>,
+    next_id: AtomicU64,
+    tombstones: Mutex<HashSet<u64>>,
+    /// The size of `tombstones`, so pops can skip locking it when it's empty.
+    num_tombstones: AtomicUsize,
+    /// Held shared by every pop from before it pops an entry until it has
+    /// checked that entry for a tombstone, and exclusively while clearing
+    /// stale tombstones, so that a tombstone is never cleared while its
+    /// entry is popped but not yet checked.
+    checking: RwLock<()>,
+}
+
+impl<T: Ord> CancellableMilkPQ<T> {
+    /// Create a new [`CancellableMilkPQ`], like [`MilkPQ::new()`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self::from_pq(MilkPQ::new())
+    }
+
+    /// Create a new [`CancellableMilkPQ`] with `limit` subqueues, like
+    /// [`MilkPQ::with_queues()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn with_queues(limit: usize) -> Self {
+        Self::from_pq(MilkPQ::with_queues(limit))
+    }
+
+    fn from_pq(pq: MilkPQ<KeyOnly<T, u64>>) -> Self {
+        CancellableMilkPQ {
+            pq,
+            next_id: AtomicU64::new(0),
+            tombstones: Mutex::default(),
+            num_tombstones: AtomicUsize::new(0),
+            checking: RwLock::default(),
+        }
+    }
+
+    /// Push an element into a subqueue, returning the id to cancel it with.
+    pub fn push(&self, t: T) -> u64 {
+        let id = self.next_id.fetch_add(1, order::COUNT);
+        self.pq.push(KeyOnly { key: t, value: id });
+        id
+    }
+
+    /// Cancel the element pushed with `id`, so that it's never popped.
+    ///
+    /// The tombstone is removed once the cancelled element is reached and
+    /// discarded. Cancelling an id that was already popped leaves a stale
+    /// tombstone behind. Those are all dropped the next time
+    /// [`strong_pop()`](Self::strong_pop()) finds the queue empty, and
+    /// whenever there are more than twice as many tombstones as queued
+    /// elements, plus some slack, this scans the queue and drops the ones
+    /// whose elements are gone. So the tombstones never take much more room
+    /// than the queue itself, even if it never drains.
+    pub fn cancel(&self, id: u64) {
+        let mut tombstones = self.tombstones.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if tombstones.insert(id) {
+            self.num_tombstones.store(tombstones.len(), order::COUNT);
+        }
+
+        if tombstones.len() > 2 * self.pq.len() + STALE_TOMBSTONE_SLACK {
+            drop(tombstones);
+            self.compact_tombstones();
+        }
+    }
+
+    /// Whether `entry` was cancelled, removing its tombstone if so.
+    fn take_tombstone(&self, entry: &KeyOnly<T, u64>) -> bool {
+        if self.num_tombstones.load(order::COUNT) == 0 {
+            return false;
+        }
+
+        let mut tombstones = self.tombstones.lock().unwrap_or_else(PoisonError::into_inner);
+        let cancelled = tombstones.remove(&entry.value);
+        self.num_tombstones.store(tombstones.len(), order::COUNT);
+        cancelled
+    }
+
+    /// Pop the maximum uncancelled element in a priority subqueue, like
+    /// [`MilkPQ::pop()`].
+    pub fn pop(&self) -> Option<T> {
+        loop {
+            let _checking = self.checking.read().unwrap_or_else(PoisonError::into_inner);
+
+            match self.pq.pop() {
+                Some(entry) if self.take_tombstone(&entry) => (),
+                entry => return entry.map(|entry| entry.key),
+            }
+        }
+    }
+
+    /// Pop an uncancelled element if any exist in any subqueue, like
+    /// [`MilkPQ::strong_pop()`].
+    pub fn strong_pop(&self) -> Option<T> {
+        loop {
+            let checking = self.checking.read().unwrap_or_else(PoisonError::into_inner);
+
+            match self.pq.strong_pop() {
+                Some(entry) if self.take_tombstone(&entry) => (),
+                Some(entry) => return Some(entry.key),
+                None => {
+                    drop(checking);
+
+                    if self.num_tombstones.load(order::COUNT) != 0 {
+                        self.clear_stale_tombstones();
+                    }
+
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Drops every tombstone if the queue is empty, since they can only be
+    /// for ids that were already popped.
+    ///
+    /// Emptiness is checked with both locks held. Holding `tombstones` means
+    /// any push that was cancelled before this is visible in the length, and
+    /// holding `checking` means no popped entry is still waiting to be
+    /// checked against its tombstone.
+    fn clear_stale_tombstones(&self) {
+        let _checking = self.checking.write().unwrap_or_else(PoisonError::into_inner);
+        let mut tombstones = self.tombstones.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if self.pq.is_empty() {
+            tombstones.clear();
+            self.num_tombstones.store(0, order::COUNT);
+        }
+    }
+
+    /// Drops every tombstone whose element isn't queued anymore.
+    ///
+    /// The locks are taken in the same order as in
+    /// [`clear_stale_tombstones()`](Self::clear_stale_tombstones()), and the
+    /// subqueues last. Holding `checking` means no popped entry is waiting to
+    /// be checked, so every id that isn't in a subqueue is gone for good.
+    /// Holding `tombstones` before locking the subqueues means any cancelled
+    /// id that was pushed is in one of them by the time they're scanned.
+    fn compact_tombstones(&self) {
+        let _checking = self.checking.write().unwrap_or_else(PoisonError::into_inner);
+        let mut tombstones = self.tombstones.lock().unwrap_or_else(PoisonError::into_inner);
+        let guards = self.pq.lock_all();
+        let live = guards
+            .iter()
+            .flat_map(|guard| guard.heap_ref().iter())
+            .map(|entry| entry.value)
+            .filter(|id| tombstones.contains(id))
+            .collect::<HashSet<_>>();
+        drop(guards);
+        *tombstones = live;
+        self.num_tombstones.store(tombstones.len(), order::COUNT);
+    }
+
+    /// The number of elements in the structure, including cancelled ones
+    /// that haven't been discarded yet.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.pq.len()
+    }
+
+    /// Whether the structure is empty, counting cancelled elements that
+    /// haven't been discarded yet.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.pq.is_empty()
+    }
+}
+
+impl<T: Ord> Default for CancellableMilkPQ<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering::Relaxed;
+    use super::*;
+
+    #[test]
+    fn cancel() {
+        let q = CancellableMilkPQ::with_queues(4);
+        let ids = (0..100).map(|i| q.push(i)).collect::<Vec<_>>();
+        q.cancel(ids[99]);
+        q.cancel(ids[50]);
+        let mut popped = Vec::new();
+
+        while let Some(t) = q.strong_pop() {
+            popped.push(t);
+        }
+
+        popped.sort_unstable();
+        assert_eq!(popped.len(), 98);
+        assert!(!popped.contains(&99));
+        assert!(!popped.contains(&50));
+        assert_eq!(q.num_tombstones.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn cancel_concurrent() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::atomic::Ordering::{Acquire, Release};
+
+        const PER_PUSHER: u64 = 50_000;
+        let q = CancellableMilkPQ::with_queues(4);
+        // One past the last element each pusher has finished cancelling.
+        let cancelled = [AtomicU64::new(0), AtomicU64::new(PER_PUSHER)];
+        let done = AtomicBool::new(false);
+
+        let mut popped = std::thread::scope(|s| {
+            let pushers = (0..)
+                .zip(&cancelled)
+                .map(|(pusher, cancelled)| {
+                    let q = &q;
+                    s.spawn(move || for t in pusher * PER_PUSHER..(pusher + 1) * PER_PUSHER {
+                        let id = q.push(t);
+
+                        // Every odd element is cancelled right away.
+                        if t % 2 == 1 {
+                            q.cancel(id);
+                            cancelled.store(t + 1, Release);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            let poppers = (0..4)
+                .map(|_| s.spawn(|| {
+                    let mut popped = Vec::new();
+
+                    while !done.load(Relaxed) {
+                        let before = [cancelled[0].load(Acquire), cancelled[1].load(Acquire)];
+
+                        if let Some(t) = q.strong_pop() {
+                            // It's only too late to cancel what was popped
+                            // before the cancel finished.
+                            let pusher = usize::from(t >= PER_PUSHER);
+                            assert!(
+                                t % 2 == 0 || t >= before[pusher],
+                                "{} was popped after being cancelled",
+                                t,
+                            );
+                            popped.push(t);
+                        }
+                    }
+
+                    popped
+                }))
+                .collect::<Vec<_>>();
+
+            for pusher in pushers {
+                pusher.join().unwrap();
+            }
+
+            done.store(true, Relaxed);
+            poppers.into_iter().flat_map(|popper| popper.join().unwrap()).collect::<Vec<_>>()
+        });
+
+        popped.extend(std::iter::from_fn(|| q.strong_pop()));
+        popped.retain(|t| t % 2 == 0);
+        popped.sort_unstable();
+        assert_eq!(popped, (0..2 * PER_PUSHER).filter(|t| t % 2 == 0).collect::<Vec<_>>());
+        assert_eq!(q.num_tombstones.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn stale_tombstone() {
+        let q = CancellableMilkPQ::with_queues(1);
+        let id = q.push(1);
+        assert_eq!(q.pop(), Some(1));
+        q.cancel(id);
+        assert_eq!(q.strong_pop(), None);
+        assert_eq!(q.num_tombstones.load(Relaxed), 0);
+        q.push(2);
+        assert_eq!(q.pop(), Some(2));
+    }
+
+    #[test]
+    fn stale_tombstones_bounded() {
+        let q = CancellableMilkPQ::with_queues(1);
+        let kept = (0..100).map(|t| q.push(t)).collect::<Vec<_>>();
+        // Some live elements are cancelled too, and must stay cancelled.
+        q.cancel(kept[0]);
+        q.cancel(kept[1]);
+
+        // The queue never drains, so strong_pop never clears the stale ones.
+        for t in 1000..11_000 {
+            let id = q.push(t);
+            assert_eq!(q.pop(), Some(t));
+            q.cancel(id);
+            let tombstones = q.tombstones.lock().unwrap().len();
+            assert!(tombstones <= 2 * q.len() + STALE_TOMBSTONE_SLACK);
+            assert_eq!(q.num_tombstones.load(Relaxed), tombstones);
+        }
+
+        let mut popped = std::iter::from_fn(|| q.pop()).collect::<Vec<_>>();
+        popped.sort_unstable();
+        assert_eq!(popped, (2..100).collect::<Vec<_>>());
+    }
+}
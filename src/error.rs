@@ -0,0 +1,87 @@
+//! Errors from the fallible [`MilkPQ`](crate::MilkPQ) operations.
+//!
+//! Both enums are `#[non_exhaustive]`, so new ways for an operation to fail
+//! can be added without breaking matches on them.
+
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+
+/// Why [`MilkPQ::try_push()`](crate::MilkPQ::try_push) didn't push an
+/// element, with the element handed back.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PushError<T> {
+    /// The queue is bounded and already full.
+    Full(T),
+}
+
+impl<T> PushError<T> {
+    /// Take back the element that wasn't pushed.
+    pub fn into_inner(self) -> T {
+        match self {
+            PushError::Full(t) => t,
+        }
+    }
+}
+
+impl<T> Display for PushError<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            PushError::Full(_) => f.write_str("the queue is full"),
+        }
+    }
+}
+
+impl<T: Debug> Error for PushError<T> {}
+
+/// Why a non-blocking pop like
+/// [`MilkPQ::try_pop_at()`](crate::MilkPQ::try_pop_at) couldn't tell
+/// whether there was anything to pop.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PopError {
+    /// A subqueue was locked by another thread, so it may not have been
+    /// empty.
+    Locked,
+}
+
+impl Display for PopError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            PopError::Locked => f.write_str("a subqueue was locked by another thread"),
+        }
+    }
+}
+
+impl Error for PopError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MilkPQ;
+
+    fn boxed<E: Error + 'static>(e: E) -> Box<dyn Error> {
+        Box::new(e)
+    }
+
+    #[test]
+    fn push_error() {
+        let e = PushError::Full(String::from("x"));
+        assert_eq!(e.to_string(), "the queue is full");
+        assert_eq!(boxed(e.clone()).to_string(), "the queue is full");
+        assert_eq!(e.into_inner(), "x");
+    }
+
+    #[test]
+    fn pop_error() {
+        fn pop(q: &MilkPQ<i32>) -> Result<Option<i32>, Box<dyn Error>> {
+            Ok(q.try_pop_at(0)?)
+        }
+
+        assert_eq!(PopError::Locked.to_string(), "a subqueue was locked by another thread");
+        assert!(boxed(PopError::Locked).source().is_none());
+        let q = MilkPQ::with_queues(1);
+        q.push(1);
+        assert_eq!(pop(&q).unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,91 @@
+//! [`ordered_float`] integration.
+
+use ordered_float::{Float, NotNan};
+pub use ordered_float::FloatIsNan;
+use super::MilkPQ;
+
+/// A [`MilkPQ`] of floats.
+///
+/// Floats aren't [`Ord`] because of NaN, so this stores them as [`NotNan`]
+/// internally and rejects NaN when pushing, while letting you push and pop
+/// plain `f32`s and `f64`s.
+#[derive(Debug, Clone, Default)]
+pub struct FloatMilkPQ<F: Float> {
+    pq: MilkPQ<NotNan<F>>,
+}
+
+impl<F: Float> FloatMilkPQ<F> {
+    /// Create a new [`FloatMilkPQ`], like [`MilkPQ::new()`].
+    #[must_use]
+    pub fn new() -> Self {
+        FloatMilkPQ { pq: MilkPQ::new() }
+    }
+
+    /// Create a new [`FloatMilkPQ`] with `limit` subqueues, like
+    /// [`MilkPQ::with_queues()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn with_queues(limit: usize) -> Self {
+        FloatMilkPQ { pq: MilkPQ::with_queues(limit) }
+    }
+
+    /// Push a float into a subqueue.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FloatIsNan`] without pushing anything if `f` is NaN.
+    pub fn push(&self, f: F) -> Result<(), FloatIsNan> {
+        self.pq.push(NotNan::new(f)?);
+        Ok(())
+    }
+
+    /// Pop the maximum float in a priority subqueue, like [`MilkPQ::pop()`].
+    pub fn pop(&self) -> Option<F> {
+        self.pq.pop().map(NotNan::into_inner)
+    }
+
+    /// Pop a float if any exist in any subqueue, like
+    /// [`MilkPQ::strong_pop()`].
+    pub fn strong_pop(&self) -> Option<F> {
+        self.pq.strong_pop().map(NotNan::into_inner)
+    }
+
+    /// The number of floats in the structure.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.pq.len()
+    }
+
+    /// Whether the structure is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.pq.is_empty()
+    }
+
+    /// Get the underlying [`MilkPQ`] of [`NotNan`]s.
+    pub fn into_inner(self) -> MilkPQ<NotNan<F>> {
+        self.pq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let q = FloatMilkPQ::with_queues(1);
+        assert!(q.push(1.5f64).is_ok());
+        assert!(q.push(-2.0).is_ok());
+        assert!(q.push(f64::INFINITY).is_ok());
+        assert_eq!(q.push(f64::NAN), Err(FloatIsNan));
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.pop(), Some(f64::INFINITY));
+        assert_eq!(q.pop(), Some(1.5));
+        assert_eq!(q.pop(), Some(-2.0));
+        assert!(q.is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+//! Queues of payloads ordered by a separate priority.
+
+use std::cmp::Ordering as CmpOrdering;
+use super::MilkPQ;
+
+/// A priority and a payload, compared by the priority alone.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyOnly<P, V> {
+    pub(crate) key: P,
+    pub(crate) value: V,
+}
+
+impl<P: Ord, V> PartialEq for KeyOnly<P, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<P: Ord, V> Eq for KeyOnly<P, V> {}
+
+impl<P: Ord, V> PartialOrd for KeyOnly<P, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P: Ord, V> Ord for KeyOnly<P, V> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// A [`MilkPQ`] of payloads `V`, each pushed with a priority `P`.
+///
+/// Only the priorities are compared, so `V` doesn't need to be [`Ord`], and
+/// payloads with equal priorities come out in no particular order.
+#[derive(Debug, Clone)]
+pub struct KeyedMilkPQ<P: Ord, V> {
+    pq: MilkPQ<KeyOnly<P, V>>,
+}
+
+impl<P: Ord, V> KeyedMilkPQ<P, V> {
+    /// Create a new [`KeyedMilkPQ`], like [`MilkPQ::new()`].
+    #[must_use]
+    pub fn new() -> Self {
+        KeyedMilkPQ { pq: MilkPQ::new() }
+    }
+
+    /// Create a new [`KeyedMilkPQ`] with `limit` subqueues, like
+    /// [`MilkPQ::with_queues()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn with_queues(limit: usize) -> Self {
+        KeyedMilkPQ { pq: MilkPQ::with_queues(limit) }
+    }
+
+    /// Push `value` with priority `key` into a subqueue.
+    pub fn push(&self, key: P, value: V) {
+        self.pq.push(KeyOnly { key, value });
+    }
+
+    /// Pop the highest priority payload in a priority subqueue, like
+    /// [`MilkPQ::pop()`].
+    pub fn pop(&self) -> Option<(P, V)> {
+        self.pq.pop().map(|kv| (kv.key, kv.value))
+    }
+
+    /// Pop a payload if any exist in any subqueue, like
+    /// [`MilkPQ::strong_pop()`].
+    pub fn strong_pop(&self) -> Option<(P, V)> {
+        self.pq.strong_pop().map(|kv| (kv.key, kv.value))
+    }
+
+    /// The number of payloads in the structure.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.pq.len()
+    }
+
+    /// Whether the structure is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.pq.is_empty()
+    }
+}
+
+impl<P: Ord, V> Default for KeyedMilkPQ<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        // Neither `Ord` nor even `PartialEq`.
+        struct Payload(&'static str);
+
+        let q = KeyedMilkPQ::with_queues(1);
+        q.push(2, Payload("b"));
+        q.push(3, Payload("c"));
+        q.push(1, Payload("a"));
+        assert_eq!(q.len(), 3);
+        let popped = std::iter::from_fn(|| q.pop()).map(|(p, v)| (p, v.0)).collect::<Vec<_>>();
+        assert_eq!(popped, [(3, "c"), (2, "b"), (1, "a")]);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn equal_priorities() {
+        let q = KeyedMilkPQ::with_queues(4);
+
+        for i in 0..100 {
+            q.push(i % 2, vec![i]);
+        }
+
+        let mut popped = std::iter::from_fn(|| q.strong_pop()).collect::<Vec<_>>();
+        assert_eq!(popped.len(), 100);
+        popped.sort_by_key(|(_, v)| v[0]);
+        assert!(popped.iter().enumerate().all(|(i, (p, v))| *p == i % 2 && v[0] == i));
+    }
+}
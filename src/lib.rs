@@ -1,393 +1,4758 @@
 //! crate docs
 
 #![warn(
-    clippy::all, clippy::pedantic, clippy::clippy::cargo_common_metadata,
-    missing_crate_level_docs, missing_debug_implementations, missing_doc_code_examples,
-    missing_docs,
+    clippy::all, clippy::pedantic, clippy::cargo_common_metadata,
+    rustdoc::missing_crate_level_docs, missing_debug_implementations, missing_docs,
 )]
-#![allow(clippy::clippy::must_use_candidate)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 
+use std::cmp::Ordering as CmpOrdering;
 use std::collections::BinaryHeap;
-use std::cell::UnsafeCell;
+use std::collections::hash_map::{HashMap, RandomState};
+use std::hash::{BuildHasher, Hash};
+use std::cell::{Cell, UnsafeCell};
 use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::sync::atomic::{spin_loop_hint, AtomicBool, Ordering::{Relaxed, Release}};
+use std::hint::spin_loop;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
+use std::time::Instant;
+use std::thread::ThreadId;
+use std::sync::atomic::AtomicUsize;
+#[cfg(not(feature = "mutex-backend"))]
+use std::sync::atomic::AtomicBool;
 use ref_thread_local::{ref_thread_local, RefThreadLocal};
 use rand_distr::Uniform;
 use rand::prelude::*;
+#[cfg(feature = "mutex-backend")]
+use parking_lot::lock_api::RawMutex as _;
+
+mod allocator;
+mod cancel;
+mod error;
+mod keyed;
+#[cfg(feature = "rayon")]
+mod par;
+#[cfg(feature = "ordered-float")]
+mod float;
+#[cfg(feature = "ordered-float")]
+pub use float::{FloatMilkPQ, FloatIsNan};
+pub use cancel::CancellableMilkPQ;
+pub use error::{PopError, PushError};
+pub use keyed::KeyedMilkPQ;
+use allocator::{Allocator, Global, Heap, HeapIntoIter};
+
+/// The memory orderings used by every atomic in the crate: the subqueue
+/// locks, the length and capacity counters, and settings like the default
+/// subqueue count that are read without a lock.
+///
+/// The `debug-seqcst` feature swaps every one of them for [`SeqCst`], which
+/// is only meant for checking whether a bug goes away with the strongest
+/// ordering. It's noticeably slower, so don't ship with it enabled.
+///
+/// [`SeqCst`]: std::sync::atomic::Ordering::SeqCst
+#[cfg(not(feature = "debug-seqcst"))]
+#[cfg_attr(feature = "mutex-backend", allow(dead_code))]
+mod order {
+    use std::sync::atomic::Ordering::{self, Acquire, Relaxed, Release};
+
+    pub const LOCK: Ordering = Acquire;
+    pub const LOCK_FAILURE: Ordering = Relaxed;
+    pub const UNLOCK: Ordering = Release;
+    pub const COUNT: Ordering = Relaxed;
+    pub const SETTING: Ordering = Relaxed;
+}
+
+#[cfg(feature = "debug-seqcst")]
+#[cfg_attr(feature = "mutex-backend", allow(dead_code))]
+mod order {
+    use std::sync::atomic::Ordering::{self, SeqCst};
+
+    pub const LOCK: Ordering = SeqCst;
+    pub const LOCK_FAILURE: Ordering = SeqCst;
+    pub const UNLOCK: Ordering = SeqCst;
+    pub const COUNT: Ordering = SeqCst;
+    pub const SETTING: Ordering = SeqCst;
+}
+
+/// How many subqueues to make per thread by default.
+///
+/// Having more subqueues than threads makes it much less likely that two
+/// threads pick the same subqueue at once, which is what keeps the CAS locks
+/// cheap. Four per thread is plenty in practice without making
+/// [`MilkPQ::strong_pop()`] scan too many empty subqueues.
+///
+/// [`MilkPQ::new()`] makes this many per CPU, which is
+/// [`MilkPQ::recommended_queues()`]. To use a different factor, pass it to
+/// [`MilkPQ::with_oversubscription()`], or override the count everywhere
+/// with [`set_default_queues()`].
+pub const DEFAULT_OVERSUBSCRIPTION: usize = 4;
+
+/// The subqueue count set by [`set_default_queues()`], or 0 if unset.
+static DEFAULT_QUEUES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the number of subqueues used by [`MilkPQ::new()`], [`Default`],
+/// [`FromIterator`], and the other constructors that don't take a subqueue
+/// count.
+///
+/// This is process-wide, so it's meant to be set once at startup rather than
+/// threading a count through every call site. Passing 0 goes back to the
+/// default of [`DEFAULT_OVERSUBSCRIPTION`] subqueues per CPU.
+pub fn set_default_queues(n: usize) {
+    DEFAULT_QUEUES.store(n, order::SETTING);
+}
+
+/// The subqueue count used by constructors that don't take one.
+///
+/// This is whatever was set with [`set_default_queues()`], capped at
+/// [`MAX_QUEUES`], or else [`MilkPQ::recommended_queues()`].
+#[must_use]
+pub fn default_queues() -> usize {
+    match DEFAULT_QUEUES.load(order::SETTING) {
+        0 => MilkPQ::<()>::recommended_queues(),
+        n => n.min(MAX_QUEUES),
+    }
+}
+
+/// The most subqueues the constructors will make.
+///
+/// Asking for more gets you this many instead. Every subqueue costs memory
+/// up front and makes [`MilkPQ::strong_pop()`], [`MilkPQ::clear()`], and
+/// the other whole-structure operations slower, and past a few thousand
+/// there's no contention left to save. If you really want more, use
+/// [`MilkPQ::with_queues_unchecked()`].
+pub const MAX_QUEUES: usize = 4096;
+
+/// The subqueue count picked by [`autosized_queues()`], once it's run.
+static AUTOSIZED_QUEUES: OnceLock<usize> = OnceLock::new();
+
+/// How many push/pop pairs each thread does per candidate count when
+/// [`autosized_queues()`] probes.
+const AUTOSIZE_PROBE_OPS: usize = 512;
+
+/// A subqueue count picked by measuring contention on this machine.
+///
+/// The first call runs a short probe: for 1, 2, 4, and 8 subqueues per CPU,
+/// one thread per CPU hammers a fresh [`MilkPQ`] with a few hundred pushes
+/// and pops, and whichever count finished fastest wins. That takes a few
+/// milliseconds and spawns threads, so it's never done unless you ask for
+/// it. The result is cached, so the probe runs at most once per process and
+/// later calls just return it.
+#[must_use]
+pub fn autosized_queues() -> usize {
+    *AUTOSIZED_QUEUES.get_or_init(|| {
+        let threads = num_cpus::get().max(2);
+
+        [1, 2, 4, 8]
+            .iter()
+            .map(|factor| (threads * factor).min(MAX_QUEUES))
+            .min_by_key(|&n| {
+                let pq = MilkPQ::with_queues(n);
+                let start = Instant::now();
+
+                std::thread::scope(|s| {
+                    for _ in 0..threads {
+                        s.spawn(|| {
+                            for i in 0..AUTOSIZE_PROBE_OPS {
+                                pq.push(i);
+                                pq.pop();
+                            }
+                        });
+                    }
+                });
+
+                start.elapsed()
+            })
+            .unwrap_or(threads * DEFAULT_OVERSUBSCRIPTION)
+    })
+}
+
+/// The distribution for picking one of `limit` subqueues, if there's more
+/// than one to pick from, so a single subqueue doesn't cost a PRNG sample.
+fn dist_for(limit: usize) -> Option<Uniform<usize>> {
+    if limit > 1 {
+        Some(Uniform::new(0, limit))
+    } else {
+        None
+    }
+}
+
+/// Waits once in a loop that's waiting on another thread.
+///
+/// Every spin in the crate goes through here, so that how to wait can be
+/// changed in one place.
+#[inline]
+fn spin() {
+    spin_loop();
+}
+
+/// Waits between retries of an operation that lost a race for a subqueue,
+/// counting how many times it had to.
+///
+/// With the `backoff` feature this is [`crossbeam_utils::Backoff`], which
+/// spins for a while and then starts yielding the thread, otherwise it just
+/// spins.
+struct Retry {
+    failures: Cell<usize>,
+    #[cfg(feature = "backoff")]
+    backoff: crossbeam_utils::Backoff,
+}
+
+impl Retry {
+    fn new() -> Self {
+        Retry {
+            failures: Cell::new(0),
+            #[cfg(feature = "backoff")]
+            backoff: crossbeam_utils::Backoff::new(),
+        }
+    }
+
+    fn wait(&self) {
+        self.failures.set(self.failures.get() + 1);
+
+        #[cfg(feature = "backoff")]
+        if self.backoff.is_completed() {
+            self.backoff.snooze();
+        } else {
+            self.backoff.spin();
+        }
+
+        #[cfg(not(feature = "backoff"))]
+        spin();
+    }
+
+    fn failures(&self) -> usize {
+        self.failures.get()
+    }
+}
+
+/// The PRNGs of a [`MilkPQ`] built with [`MilkPQ::with_seed_and_queues()`],
+/// one per thread.
+///
+/// Each thread's PRNG is seeded from `seed` and the index of the thread in
+/// the order threads first used the queue.
+struct SeededRngs {
+    seed: u64,
+    rngs: Mutex<HashMap<ThreadId, SmallRng>>,
+    threads: AtomicUsize,
+}
+
+impl SeededRngs {
+    fn new(seed: u64) -> Self {
+        SeededRngs { seed, rngs: Mutex::default(), threads: AtomicUsize::new(0) }
+    }
+
+    /// Takes this thread's PRNG out, so that it isn't borrowed from the map
+    /// while it's being used.
+    fn take(&self) -> SmallRng {
+        let id = std::thread::current().id();
+        let rng = self.rngs.lock().unwrap_or_else(PoisonError::into_inner).remove(&id);
+
+        rng.unwrap_or_else(|| {
+            let index = self.threads.fetch_add(1, order::COUNT) as u64;
+            SmallRng::seed_from_u64(self.seed.wrapping_add(index))
+        })
+    }
+
+    fn put_back(&self, rng: SmallRng) {
+        let id = std::thread::current().id();
+        self.rngs.lock().unwrap_or_else(PoisonError::into_inner).insert(id, rng);
+    }
+}
+
+/// A callback registered with [`MilkPQ::set_contention_callback()`].
+type ContentionCallback = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// The lock failure rate [`MilkPQ::auto_tune()`] aims for by default.
+pub const DEFAULT_TUNE_TARGET: f64 = 0.05;
+
+/// How many lock attempts [`MilkPQ::auto_tune()`] needs to see before it
+/// trusts the failure rate enough to act on it.
+const TUNE_MIN_ATTEMPTS: usize = 64;
+
+/// Lock contention counters, see [`MilkPQ::stats()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// How many times a subqueue lock was tried.
+    pub lock_attempts: usize,
+    /// How many of those tries failed because another thread held the lock.
+    pub lock_failures: usize,
+}
+
+impl Stats {
+    /// The fraction of lock attempts that failed, or 0 if there were none.
+    #[allow(clippy::cast_precision_loss)]
+    #[must_use]
+    pub fn failure_rate(&self) -> f64 {
+        if self.lock_attempts == 0 {
+            0.0
+        } else {
+            self.lock_failures as f64 / self.lock_attempts as f64
+        }
+    }
+}
+
+/// How many elements per subqueue [`MilkPQ::extend_ref()`] buckets at once.
+const EXTEND_CHUNK_PER_QUEUE: usize = 64;
+
+/// The most elements `FromIterator` reserves room for based on an
+/// iterator's upper size bound alone.
+const FROM_ITER_MAX_HINT: usize = 1 << 16;
+
+/// The number of subqueues sampled by [`MilkPQ::len_approx()`].
+pub const LEN_APPROX_SAMPLES: usize = 8;
 
 ref_thread_local! {
     static managed PRNG: SmallRng = SmallRng::from_entropy();
 }
 
+thread_local! {
+    /// The subqueue index this thread tries first when pushing, modulo the
+    /// number of subqueues. It starts out random and moves to wherever the
+    /// last contended push landed.
+    static HOME: Cell<usize> = Cell::new(PRNG.borrow_mut().gen());
+}
+
 /// docs
-pub struct MilkPQ<T: Ord> {
-    queues: Box<[Queue<T>]>,
-    dist: Uniform<usize>,
+///
+/// # Thread safety
+///
+/// A [`MilkPQ`] is [`Send`] and [`Sync`] whenever `T` is [`Send`], just like
+/// a [`Mutex`], so it can be shared between threads behind an [`Arc`]:
+///
+/// ```
+/// fn assert_send_sync<T: Send + Sync>() {}
+/// assert_send_sync::<milkpq::MilkPQ<i32>>();
+/// assert_send_sync::<milkpq::MilkPQ<std::cell::Cell<i32>>>();
+/// ```
+///
+/// Elements that can't be sent between threads make it neither, even if
+/// they're [`Sync`], since pushing and popping through a shared queue moves
+/// them between threads:
+///
+/// ```compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+/// assert_send::<milkpq::MilkPQ<std::rc::Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<milkpq::MilkPQ<std::rc::Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// use std::marker::PhantomData;
+/// use std::sync::MutexGuard;
+///
+/// #[derive(PartialEq, Eq, PartialOrd, Ord)]
+/// struct NotSend(PhantomData<MutexGuard<'static, ()>>);
+///
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<milkpq::MilkPQ<NotSend>>();
+/// ```
+///
+/// With a custom allocator, `A` has to be [`Send`] as well, like the
+/// allocator of a `Vec` inside a [`Mutex`].
+///
+/// # Allocators
+///
+/// With the nightly-only `allocator_api` feature, the subqueues' heaps can
+/// be allocated with any `std::alloc::Allocator`, given to `new_in()`,
+/// `with_queues_in()` or `with_capacity_queues_and_hasher_in()` and kept as
+/// the `A` parameter. Each subqueue allocates with its own clone of it.
+/// Methods that return a [`Vec`] still return one from the global allocator.
+/// With the feature on, [`Vec::from`] and the `into_sorted_vec` methods can't
+/// reuse a subqueue's buffer for it, since that could be in any allocator, so
+/// they copy the elements out instead. Without the feature, `A` is always
+/// the global allocator.
+pub struct MilkPQ<T: Ord, S = RandomState, A: Allocator = Global> {
+    queues: Box<[Queue<T, A>]>,
+    /// Picks a random subqueue, or [`None`] if there's only one to pick.
+    dist: Option<Uniform<usize>>,
+    hasher: S,
+    /// The number of elements, kept up to date only if `track_len` is set.
+    len: AtomicUsize,
+    track_len: bool,
+    /// The sum of the subqueues' capacities, shared with each subqueue so
+    /// that it can be updated under the lock whenever one reallocates.
+    capacity: Arc<AtomicUsize>,
+    bound: Option<usize>,
+    tune_target: f64,
+    weighted_pop: bool,
+    /// Per-instance PRNGs to use instead of the thread-local one, if seeded.
+    seeded: Option<SeededRngs>,
+    /// The callback for [`MilkPQ::set_contention_callback()`], and its
+    /// threshold mirrored in an atomic so the retry loops can check it
+    /// without locking. `usize::MAX` means there's no callback.
+    contention: RwLock<Option<ContentionCallback>>,
+    contention_threshold: AtomicUsize,
 }
 
-impl<T: Ord + Clone> Clone for MilkPQ<T> {
+impl<T: Ord + Clone, S: Clone, A: Allocator + Clone> Clone for MilkPQ<T, S, A> {
     fn clone(&self) -> Self {
-        MilkPQ { queues: self.queues.clone(), dist: self.dist }
+        self.with_cloned_queues(self.queues.clone())
     }
 
+    /// Reuses each subqueue's allocation when `source` has as many subqueues
+    /// as `self`, and only makes new ones if the counts differ.
     fn clone_from(&mut self, source: &Self) {
-        self.queues.clone_from(&source.queues);
+        if self.queues.len() == source.queues.len() {
+            for (queue, source) in self.queues.iter_mut().zip(source.queues.iter()) {
+                queue.clone_from(source);
+            }
+        } else {
+            self.queues = source.queues.iter().cloned().collect();
+        }
+
         self.dist = source.dist;
+        self.hasher.clone_from(&source.hasher);
+        self.bound = source.bound;
+        self.track_len = source.track_len;
+        self.tune_target = source.tune_target;
+        self.weighted_pop = source.weighted_pop;
+        self.seeded = source.seeded.as_ref().map(|seeded| SeededRngs::new(seeded.seed));
+        *self.contention.get_mut().unwrap_or_else(PoisonError::into_inner) = source.contention_callback();
+        *self.contention_threshold.get_mut() = source.contention_threshold.load(order::SETTING);
+        self.recount();
     }
 }
 
-impl<T: Ord> FromIterator<T> for MilkPQ<T> {
+/// Collecting is the bulk way to build a [`MilkPQ`].
+///
+/// Every element is placed before any heap is built, and each subqueue is
+/// then heapified at once in `O(n)`, which is several times faster than
+/// pushing the same elements one at a time in `O(n log n)`. The catch is
+/// that the queue only exists once `iter` is used up, so nothing can be
+/// popped from it in the meantime. To consume elements while they're still
+/// being produced, build the queue first and feed it with
+/// [`MilkPQ::push()`] or [`MilkPQ::extend_ref()`] instead, which makes
+/// elements poppable as soon as they're pushed, or a chunk at a time for
+/// [`MilkPQ::extend_ref()`].
+///
+/// Collecting doesn't buffer the input outside the subqueues, so its peak
+/// memory is the same as the finished queue's.
+impl<T: Ord, S: Default> FromIterator<T> for MilkPQ<T, S> {
+    /// Deals the elements out round-robin straight into each subqueue's
+    /// buffer as they come, then heapifies each buffer in place in `O(n)`.
+    /// Nothing is buffered outside the subqueues, so the peak memory is just
+    /// the queue itself.
     fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         let iter = iter.into_iter();
-        let pq = MilkPQ::with_capacity(iter.size_hint().0);
-        
-        for t in iter {
-            pq.push(t);
+        let limit = default_queues();
+        // Reserving for the upper bound saves regrowing when the lower bound
+        // undersells it, but an upper bound isn't a promise, so only trust it
+        // up to a point past the lower bound.
+        let hint = match iter.size_hint() {
+            (lower, Some(upper)) => upper.min(lower.max(FROM_ITER_MAX_HINT)),
+            (lower, None) => lower,
+        };
+        // The capacity is per subqueue, so split the hint between them rather
+        // than reserving the whole hint in every one.
+        let cap = hint.div_ceil(limit);
+        MilkPQ::<T, S>::check_capacity(cap.checked_mul(limit));
+        let mut bufs = std::iter::repeat_with(|| Vec::with_capacity(cap)).take(limit).collect::<Vec<_>>();
+
+        for (i, t) in iter.enumerate() {
+            bufs[i % limit].push(t);
         }
 
-        pq
+        let queues = bufs.into_iter().map(|buf| Queue::new(BinaryHeap::from(buf))).collect();
+        MilkPQ::from_queues(queues, S::default())
     }
 }
 
-impl<T: Ord> From<MilkPQ<T>> for Vec<T> {
-    fn from(pq: MilkPQ<T>) -> Self {
-        let mut vec = Vec::new();
+impl<T: Ord, S, A: Allocator + Clone> From<MilkPQ<T, S, A>> for Vec<T> {
+    fn from(pq: MilkPQ<T, S, A>) -> Self {
+        let len = pq.len();
+        let mut queues = pq.queues.into_vec().into_iter();
+        // Reusing the first subqueue's buffer means a single subqueue isn't
+        // copied at all, unless it's in a custom allocator.
+        let mut vec = queues.next().map_or_else(Vec::new, |queue| allocator::into_vec::<T, A>(queue.pq.into_inner()));
+        vec.reserve(len.saturating_sub(vec.len()));
 
-        for pq in pq.queues.into_vec() {
-            vec.extend(pq);
+        for queue in queues {
+            vec.extend(queue);
         }
 
         vec
     }
 }
 
-impl<T: Ord> IntoIterator for MilkPQ<T> {
+impl<T: Ord, S, A: Allocator + Clone> IntoIterator for MilkPQ<T, S, A> {
     type Item = T;
-    type IntoIter = std::vec::IntoIter<T>;
+    type IntoIter = IntoIter<T, A>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        Vec::into_iter(self.into())
+    fn into_iter(mut self) -> Self::IntoIter {
+        IntoIter {
+            remaining: self.queues.iter_mut().map(|q| q.pq.get_mut().len()).sum(),
+            queues: self.queues.into_vec().into_iter(),
+            current: None,
+        }
+    }
+}
+
+/// An owning iterator over the elements of a [`MilkPQ`], in no particular
+/// order.
+///
+/// Created by [`MilkPQ::into_iter()`](IntoIterator::into_iter()). This goes
+/// through the subqueues one at a time in heap order, so it never collects
+/// the whole structure up front.
+#[derive(Debug)]
+pub struct IntoIter<T: Ord, A: Allocator = Global> {
+    queues: std::vec::IntoIter<Queue<T, A>>,
+    current: Option<HeapIntoIter<T, A>>,
+    remaining: usize,
+}
+
+impl<T: Ord, A: Allocator + Clone> Iterator for IntoIter<T, A> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(t) = self.current.as_mut().and_then(Iterator::next) {
+                self.remaining -= 1;
+                return Some(t);
+            }
+
+            self.current = Some(self.queues.next()?.into_iter());
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
-impl<T: Ord> Extend<T> for MilkPQ<T> {
+impl<T: Ord, A: Allocator + Clone> ExactSizeIterator for IntoIter<T, A> {}
+
+impl<T: Ord, A: Allocator + Clone> std::iter::FusedIterator for IntoIter<T, A> {}
+
+impl<T: Ord, S, A: Allocator + Clone> Extend<T> for MilkPQ<T, S, A> {
     fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
         self.extend_ref(iter);
     }
 }
 
-impl<T: Ord> Default for MilkPQ<T> {
+impl<'a, T: Ord + Copy + 'a, S, A: Allocator + Clone> Extend<&'a T> for MilkPQ<T, S, A> {
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        self.extend_copied(iter);
+    }
+}
+
+impl<T: Ord, S: Default> Default for MilkPQ<T, S> {
     fn default() -> Self {
-        MilkPQ::new()
+        MilkPQ::with_hasher(S::default())
     }
 }
 
-impl<T: Ord + Debug> Debug for MilkPQ<T> {
+/// Formats each subqueue, or `<locked>` for any that another thread has
+/// locked at the time, so it never waits. See [`MilkPQ::debug_full()`] for
+/// a complete dump.
+impl<T: Ord + Debug, S, A: Allocator> Debug for MilkPQ<T, S, A> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         f.debug_list().entries(self.queues.as_ref()).finish()
     }
 }
 
+/// The [`Debug`] dump returned by [`MilkPQ::debug_full()`].
+pub struct DebugFull<'a, T: Ord, S, A: Allocator = Global>(&'a MilkPQ<T, S, A>);
+
+impl<T: Ord + Debug, S, A: Allocator + Clone> Debug for DebugFull<'_, T, S, A> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_list().entries(self.0.queues.iter().map(BlockingDebug)).finish()
+    }
+}
+
+/// Formats a subqueue, waiting for its lock.
+struct BlockingDebug<'a, T: Ord, A: Allocator>(&'a Queue<T, A>);
+
+impl<T: Ord + Debug, A: Allocator + Clone> Debug for BlockingDebug<'_, T, A> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.0.with_locked(|pq| pq.fmt(f))
+    }
+}
+
 impl<T: Ord> MilkPQ<T> {
     /// Create a new [`MilkPQ`] priority queue.
+    #[must_use]
     pub fn new() -> Self {
-        Self::with_queues(num_cpus::get() * 4)
+        Self::with_queues(default_queues())
+    }
+
+    /// The subqueue count picked when nothing overrides it: one
+    /// [`DEFAULT_OVERSUBSCRIPTION`] per CPU, capped at [`MAX_QUEUES`].
+    ///
+    /// This is what [`new()`] and the other constructors that don't take a
+    /// count use, unless [`set_default_queues()`] was called.
+    #[must_use]
+    pub fn recommended_queues() -> usize {
+        (num_cpus::get() * DEFAULT_OVERSUBSCRIPTION).min(MAX_QUEUES)
+    }
+
+    /// Create a new [`MilkPQ`] with [`autosized_queues()`] subqueues.
+    ///
+    /// The first call measures which subqueue count works best on this
+    /// machine, which takes a few milliseconds. See [`autosized_queues()`].
+    #[must_use]
+    pub fn new_autosized() -> Self {
+        Self::with_queues(autosized_queues())
+    }
+
+    /// Create a new [`MilkPQ`] holding just `t`.
+    ///
+    /// This is shorthand for [`new()`] followed by [`push()`], for seeding a
+    /// queue with a single root, like in a graph search.
+    #[must_use]
+    pub fn singleton(t: T) -> Self {
+        Self::from_one_with_queues(t, default_queues())
     }
 
     /// Create a new [`MilkPQ`] with each subqueue having `cap` capacity.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the total capacity overflows, see
+    /// [`with_capacity_and_queues()`].
+    #[must_use]
     pub fn with_capacity(cap: usize) -> Self {
-        Self::with_capacity_and_queues(cap, num_cpus::get() * 4)
+        Self::with_capacity_and_queues(cap, default_queues())
     }
 
     /// Create a new [`MilkPQ`] with a given number of subqueues.
+    ///
+    /// `limit` is capped at [`MAX_QUEUES`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
     pub fn with_queues(limit: usize) -> Self {
-        let queues = std::iter::repeat_with(|| Queue::new(BinaryHeap::new()))
-            .take(limit)
-            .collect::<Vec<_>>()
-            .into_boxed_slice();
-        
-        MilkPQ { queues, dist: Uniform::new(0, limit) }
+        Self::with_queues_and_hasher(limit, RandomState::new())
+    }
+
+    /// Create a new [`MilkPQ`] with `limit` subqueues that picks subqueues
+    /// with PRNGs seeded from `seed`.
+    ///
+    /// Each thread gets its own PRNG, seeded from `seed` and the thread's
+    /// index in the order threads first used this queue, and pushes skip the
+    /// per-thread home subqueue, which isn't seeded. So which subqueues a
+    /// thread tries is reproducible, which is meant for tests. Full
+    /// determinism with several threads also needs the threads' scheduling
+    /// and the order they first touch the queue to be fixed, which is out of
+    /// this crate's hands. The PRNGs are kept behind a mutex, so this is
+    /// much slower than the thread-local PRNG under contention.
+    ///
+    /// Clones start over with fresh PRNGs from the same `seed`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn with_seed_and_queues(seed: u64, limit: usize) -> Self {
+        let mut pq = Self::with_queues(limit);
+        pq.seeded = Some(SeededRngs::new(seed));
+        pq
+    }
+
+    /// Like [`singleton()`], but with `limit` subqueues, like
+    /// [`with_queues()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn from_one_with_queues(t: T, limit: usize) -> Self {
+        let pq = Self::with_queues(limit);
+        pq.push(t);
+        pq
+    }
+
+    /// Like [`with_queues()`], but without capping `limit` at
+    /// [`MAX_QUEUES`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0, or if there isn't memory for `limit`
+    /// subqueues. That's checked before making any of them, so an absurd
+    /// `limit` panics instead of aborting the process.
+    #[must_use]
+    pub fn with_queues_unchecked(limit: usize) -> Self {
+        Self::from_parts(0, limit, RandomState::new(), Global)
+    }
+
+    /// Create a new [`MilkPQ`] sized for `n_threads` threads using it at once.
+    ///
+    /// This makes [`DEFAULT_OVERSUBSCRIPTION`] subqueues per thread, same as
+    /// the default for each CPU.
+    /// See [`with_oversubscription()`] to pick the factor.
+    #[must_use]
+    pub fn with_queues_for_threads(n_threads: usize) -> Self {
+        Self::with_oversubscription(n_threads, DEFAULT_OVERSUBSCRIPTION)
+    }
+
+    /// Create a new [`MilkPQ`] with `factor` subqueues for each of
+    /// `n_threads` threads.
+    ///
+    /// A bigger factor means less lock contention but more subqueues for
+    /// [`strong_pop()`] and friends to go through.
+    #[must_use]
+    pub fn with_oversubscription(n_threads: usize, factor: usize) -> Self {
+        Self::with_queues(n_threads * factor)
     }
 
     /// Create a new [`MilkPQ`] with a given capacity and subqueue count.
     ///
     /// See [`with_capacity()`] and [`with_queues()`], as this is just a
     /// combination of the two.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0, or if the total capacity overflows, see
+    /// [`with_capacity_queues_and_hasher()`].
+    #[must_use]
     pub fn with_capacity_and_queues(cap: usize, limit: usize) -> Self {
-        let queues = std::iter::repeat_with(|| Queue::new(BinaryHeap::with_capacity(cap)))
-            .take(limit)
-            .collect::<Vec<_>>()
-            .into_boxed_slice();
-        
-        MilkPQ { queues, dist: Uniform::new(0, limit) }
+        Self::with_capacity_queues_and_hasher(cap, limit, RandomState::new())
     }
 
-    /// Push an element into a subqueue.
-    pub fn push(&self, mut t: T) {
-        let mut i = PRNG.borrow_mut().sample(self.dist);
-        
-        while let Err(t2) = self.queues[i].try_push(t) {
-            t = t2;
-            i = PRNG.borrow_mut().sample(self.dist);
-            spin_loop_hint();
-        }
+    /// Create a new [`MilkPQ`] with one subqueue per entry of `caps`, each
+    /// with that much capacity.
+    ///
+    /// This is for pre-sizing subqueues you know will be busier than others,
+    /// like the [`push_to()`] targets of a hot thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `caps` is empty, see [`with_capacities_and_hasher()`].
+    #[must_use]
+    pub fn with_capacities(caps: &[usize]) -> Self {
+        Self::with_capacities_and_hasher(caps, RandomState::new())
     }
 
-    /// Pop the maximum element in a priority subqueue.
+    /// Create a new [`MilkPQ`] that holds at most `bound` elements.
     ///
-    /// This works by trying to lock a random subqueue and popping an element
-    /// from that. Thus, this can have spurious [`None`]s when some subqueues
-    /// are empty while others are not. For a function that is guaranteed to
-    /// pop an element if any exist in any subqueues, see [`strong_pop()`].
-    pub fn pop(&self) -> Option<T> {
-        let mut i = PRNG.borrow_mut().sample(self.dist);
-        let mut t;
-
-        while {t = self.queues[i].try_pop(); t.is_err()} {
-            i = PRNG.borrow_mut().sample(self.dist);
-            spin_loop_hint();
-        }
-
-        t.unwrap()
+    /// See [`push()`] and [`try_push()`] for how pushing into a full queue
+    /// behaves.
+    #[must_use]
+    pub fn with_bound(bound: usize) -> Self {
+        Self::with_bound_and_queues(bound, default_queues())
     }
 
-    /// Pop an element from the priority queue, but non-spuriously.
+    /// Create a new bounded [`MilkPQ`] with a given subqueue count.
     ///
-    /// This will check every subqueue until it finds some element (*not
-    /// necessarily the maximum in the whole structure*) and returns it, or
-    /// will return [`None`]. Thus, it returns [`None`] iff the structure is
-    /// truly empty.
-    pub fn strong_pop(&self) -> Option<T> {
-        let mut t;
+    /// See [`with_bound()`] and [`with_queues()`], as this is just a
+    /// combination of the two.
+    #[must_use]
+    pub fn with_bound_and_queues(bound: usize, limit: usize) -> Self {
+        let mut pq = Self::with_queues(limit);
+        pq.bound = Some(bound);
+        pq
+    }
 
-        for queue in self.queues.as_ref() {
-            while {t = queue.try_pop(); t.is_err()} {
-                spin_loop_hint();
-            }
+    /// Collect `iter` into a new bounded [`MilkPQ`], returning the elements
+    /// that didn't fit alongside it.
+    ///
+    /// Elements are pushed with [`try_push()`] in order until the queue is
+    /// full, and everything after that goes into the overflow [`Vec`], in
+    /// order. So this keeps the first `bound` elements of `iter`, not the
+    /// greatest.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn from_iter_bounded<I: IntoIterator<Item = T>>(iter: I, bound: usize, limit: usize) -> (Self, Vec<T>) {
+        let pq = Self::with_bound_and_queues(bound, limit);
+        let mut iter = iter.into_iter();
+        let mut overflow = Vec::new();
 
-            let t = t.unwrap();
-            if t.is_some() {
-                return t;
+        for t in iter.by_ref() {
+            if let Err(e) = pq.try_push(t) {
+                // Nothing else can pop, so nothing more will fit.
+                overflow.push(e.into_inner());
+                break;
             }
         }
 
-        None
+        overflow.extend(iter);
+        (pq, overflow)
     }
+}
 
-    /// Turns `self` into a descending sorted [`Vec`].
-    pub fn into_sorted_vec(self) -> Vec<T> {
-        let mut vec = Vec::from(self);
-        vec.sort_unstable_by(|l, r| l.cmp(r).reverse());
-        vec
+impl<T: Ord, S> MilkPQ<T, S> {
+    /// Create a new [`MilkPQ`] which uses `hasher` to place elements pushed
+    /// with [`push_keyed()`].
+    #[must_use]
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_queues_and_hasher(default_queues(), hasher)
     }
 
-    /// Clears all subqueues in the structure.
-    pub fn clear(&self) {
-        for queue in self.queues.as_ref() {
-            queue.clear();
-        }
+    /// Create a new [`MilkPQ`] with a given number of subqueues and hasher.
+    ///
+    /// `limit` is capped at [`MAX_QUEUES`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn with_queues_and_hasher(limit: usize, hasher: S) -> Self {
+        Self::with_capacity_queues_and_hasher(0, limit, hasher)
     }
 
-    /// Empty the contents of `self` into a [`Vec`] and leave `self` empty.
-    pub fn drain(&mut self) -> Vec<T> {
-        let mut vec = Vec::new();
-
-        for queue in self.queues.as_mut() {
-            vec.extend(queue.take())
-        }
-
-        vec
+    /// Create a new [`MilkPQ`] with a given capacity, subqueue count, and
+    /// hasher.
+    ///
+    /// `limit` is capped at [`MAX_QUEUES`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    ///
+    /// Panics with `"capacity overflow"` if the combined capacity of all of
+    /// the subqueues (`cap * limit` elements) can't be allocated, rather than
+    /// leaving it to the allocator to abort.
+    #[must_use]
+    pub fn with_capacity_queues_and_hasher(cap: usize, limit: usize, hasher: S) -> Self {
+        Self::from_parts(cap, limit.min(MAX_QUEUES), hasher, Global)
     }
 
-    /// Extend `self` using an [`IntoIterator`].
+    /// Create a new [`MilkPQ`] with one subqueue per entry of `caps`, each
+    /// with that much capacity, and a given hasher.
     ///
-    /// Exactly like [`Extend`], except it doesn't mutably borrow `self`.
-    pub fn extend_ref<I: IntoIterator<Item = T>>(&self, iter: I) {
-        for t in iter {
-            self.push(t);
-        }
-    }
-}
+    /// Unlike the other constructors, this makes exactly `caps.len()`
+    /// subqueues, even past [`MAX_QUEUES`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `caps` is empty, or with `"capacity overflow"` if the
+    /// combined capacity can't be allocated.
+    #[must_use]
+    pub fn with_capacities_and_hasher(caps: &[usize], hasher: S) -> Self {
+        assert!(!caps.is_empty(), "a MilkPQ needs at least one subqueue");
+        Self::check_capacity(caps.iter().try_fold(0usize, |sum, &cap| sum.checked_add(cap)));
+
+        let queues = caps
+            .iter()
+            .map(|&cap| Queue::new(BinaryHeap::with_capacity(cap)))
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
 
-struct Queue<T: Ord> {
-    pq: UnsafeCell<BinaryHeap<T>>,
-    cas_lock: AtomicBool,
+        Self::from_queues(queues, hasher)
+    }
 }
 
-unsafe impl<T: Ord + Send> Send for Queue<T> {}
-unsafe impl<T: Ord + Sync> Sync for Queue<T> {}
+#[cfg(feature = "allocator_api")]
+impl<T: Ord, A: Allocator + Clone> MilkPQ<T, RandomState, A> {
+    /// Like [`new()`](MilkPQ::new), but the subqueues' heaps are allocated
+    /// with `alloc`.
+    #[must_use]
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_queues_in(default_queues(), alloc)
+    }
 
-impl<T: Ord> IntoIterator for Queue<T> {
-    type Item = T;
-    type IntoIter = std::collections::binary_heap::IntoIter<T>;
+    /// Like [`with_queues()`](MilkPQ::with_queues), but the subqueues' heaps
+    /// are allocated with `alloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    #[must_use]
+    pub fn with_queues_in(limit: usize, alloc: A) -> Self {
+        Self::with_capacity_queues_and_hasher_in(0, limit, RandomState::new(), alloc)
+    }
+}
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.pq.into_inner().into_iter()
+#[cfg(feature = "allocator_api")]
+impl<T: Ord, S, A: Allocator + Clone> MilkPQ<T, S, A> {
+    /// Like [`with_capacity_queues_and_hasher()`], but the subqueues' heaps
+    /// are allocated with `alloc`.
+    ///
+    /// Each subqueue gets its own clone of `alloc`, and everything that
+    /// grows, shrinks or replaces a subqueue's heap later on allocates with
+    /// that clone.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0, or with `"capacity overflow"` if the combined
+    /// capacity can't be allocated.
+    ///
+    /// [`with_capacity_queues_and_hasher()`]: MilkPQ::with_capacity_queues_and_hasher
+    #[must_use]
+    pub fn with_capacity_queues_and_hasher_in(cap: usize, limit: usize, hasher: S, alloc: A) -> Self {
+        Self::from_parts(cap, limit.min(MAX_QUEUES), hasher, alloc)
     }
 }
 
-impl<T: Ord + Clone> Clone for Queue<T> {
-    fn clone(&self) -> Self {
-        while self.cas_lock.compare_exchange_weak(false, true, Release, Relaxed).is_err() {
-            spin_loop_hint();
-        }
+impl<T: Ord, S, A: Allocator + Clone> MilkPQ<T, S, A> {
+    fn from_parts(cap: usize, limit: usize, hasher: S, alloc: A) -> Self {
+        assert!(limit > 0, "a MilkPQ needs at least one subqueue");
+        Self::check_capacity(cap.checked_mul(limit));
 
-        let pq = UnsafeCell::new(unsafe { self.pq.get().as_ref() }.unwrap().clone());
-        let cas_lock = AtomicBool::new(false);
-        self.cas_lock.store(false, Release);
-        Queue { pq, cas_lock }
+        Self::from_queues(Queue::many(limit, cap, alloc).into_boxed_slice(), hasher)
     }
 
-    fn clone_from(&mut self, source: &Self) {
-        while source.cas_lock.compare_exchange_weak(false, true, Release, Relaxed).is_err() {
-            spin_loop_hint();
-        }
+    /// Panics if `total` elements (or [`None`] for an overflowed count) can't
+    /// be allocated.
+    fn check_capacity(total: Option<usize>) {
+        let bytes = total.and_then(|n| n.checked_mul(std::mem::size_of::<T>()));
+        assert!(
+            matches!(bytes, Some(bytes) if isize::try_from(bytes).is_ok()),
+            "capacity overflow",
+        );
+    }
 
-        unsafe { self.pq.get().as_mut() }
-            .unwrap()
-            .clone_from(unsafe { source.pq.get().as_ref() }.unwrap());
+    fn from_queues(queues: Box<[Queue<T, A>]>, hasher: S) -> Self {
+        let mut pq = MilkPQ {
+            dist: dist_for(queues.len()),
+            queues,
+            hasher,
+            len: AtomicUsize::new(0),
+            track_len: true,
+            capacity: Arc::default(),
+            bound: None,
+            tune_target: DEFAULT_TUNE_TARGET,
+            weighted_pop: false,
+            seeded: None,
+            contention: RwLock::new(None),
+            contention_threshold: AtomicUsize::new(usize::MAX),
+        };
 
-        source.cas_lock.store(false, Release);
+        pq.recount();
+        pq
     }
-}
 
-impl<T: Ord + Debug> Debug for Queue<T> {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        while self.cas_lock.compare_exchange_weak(false, true, Release, Relaxed).is_err() {
-            spin_loop_hint();
+    /// Push an element into a subqueue.
+    ///
+    /// Each thread first tries its own "home" subqueue, which makes an
+    /// uncontended push a single CAS. Only if that subqueue is locked does it
+    /// fall back to trying random subqueues, and the one it lands on becomes
+    /// its new home, so threads that keep colliding spread out. This means a
+    /// single thread's pushes mostly go to one subqueue, so when few threads
+    /// push, [`pop()`] from another subqueue returns [`None`] more often; use
+    /// [`strong_pop()`] if that matters.
+    ///
+    /// If `self` is bounded and full, this spins until another thread pops
+    /// an element and makes room. See [`try_push()`] for a version that
+    /// doesn't wait.
+    #[inline]
+    pub fn push(&self, t: T) {
+        while !self.reserve_room() {
+            spin();
         }
 
-        let fmt = unsafe { self.pq.get().as_ref() }.unwrap().fmt(f);
-        self.cas_lock.store(false, Release);
-        fmt
+        self.push_reserved(t);
     }
-}
 
-impl<T: Ord> Queue<T> {
-    fn new(pq: BinaryHeap<T>) -> Self {
-        Queue {
-            pq: UnsafeCell::new(pq),
-            cas_lock: AtomicBool::new(false),
+    /// Push an element into a subqueue, unless `self` is full.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PushError::Full`] with `t` if `self` is bounded and already
+    /// full.
+    #[inline]
+    pub fn try_push(&self, t: T) -> Result<(), PushError<T>> {
+        if self.reserve_room() {
+            self.push_reserved(t);
+            Ok(())
+        } else {
+            Err(PushError::Full(t))
         }
     }
 
-    #[must_use = "must check if CAS failed"]
-    fn try_push(&self, t: T) -> Result<(), T> {
-        match self.cas_lock.compare_exchange_weak(false, true, Release, Relaxed) {
-            Ok(_) => {
-                unsafe { self.pq.get().as_mut() }.unwrap().push(t);
-                self.cas_lock.store(false, Release);
-                Ok(())
-            }
-            Err(_) => Err(t),
+    #[inline]
+    fn push_reserved(&self, t: T) {
+        if self.seeded.is_some() {
+            let (_, failures) = self.with_rng(|rng| {
+                let first = self.sample_index(rng);
+                self.push_sampled(t, rng, first)
+            });
+            self.contended(failures);
+            return;
         }
+
+        let home = HOME.with(Cell::get) % self.queues.len();
+
+        // The fast path: a single CAS on the home subqueue, with no sampling.
+        let t = match self.queues[home].try_push(t) {
+            Ok(()) => return,
+            Err(t) => t,
+        };
+
+        // Borrowed once for the whole retry loop rather than per sample.
+        let mut prng = PRNG.borrow_mut();
+        let first = self.resample(&mut *prng, home);
+        let (i, failures) = self.push_sampled(t, &mut *prng, first);
+        drop(prng);
+        HOME.with(|home| home.set(i));
+        // Counting the failed try on the home subqueue.
+        self.contended(failures + 1);
     }
 
-    #[must_use = "must check if CAS failed"]
-    fn try_pop(&self) -> Result<Option<T>, ()> {
-        match self.cas_lock.compare_exchange_weak(false, true, Release, Relaxed) {
-            Ok(_) => {
-                let r = unsafe { self.pq.get().as_mut() }.unwrap().pop();
-                self.cas_lock.store(false, Release);
-                Ok(r)
-            }
-            Err(_) => Err(()),
+    /// Like [`push()`], but picks subqueues with `rng` instead of the
+    /// thread-local PRNG.
+    ///
+    /// Every subqueue is picked by sampling `rng`, without the home subqueue
+    /// fast path, so with a seeded `rng` and no other threads the sequence of
+    /// subqueues is reproducible. It also saves the thread-local borrow when a
+    /// hot loop already has an RNG of its own.
+    pub fn sample_push<R: Rng + ?Sized>(&self, t: T, rng: &mut R) {
+        while !self.reserve_room() {
+            spin();
         }
+
+        let first = self.sample_index(rng);
+        let (_, failures) = self.push_sampled(t, rng, first);
+        self.contended(failures);
     }
 
-    fn clear(&self) {
-        while self.cas_lock.compare_exchange_weak(false, true, Release, Relaxed).is_err() {
-            spin_loop_hint();
+    /// Pushes `t` into the `i`th subqueue, or the next ones picked by `rng`
+    /// if it's locked, and returns where it went and how many tries failed.
+    #[inline]
+    fn push_sampled<R: Rng + ?Sized>(&self, mut t: T, rng: &mut R, mut i: usize) -> (usize, usize) {
+        let retry = Retry::new();
+
+        while let Err(t2) = self.queues[i].try_push(t) {
+            t = t2;
+            i = self.resample(rng, i);
+            retry.wait();
         }
 
-        unsafe { self.pq.get().as_mut() }.unwrap().clear();
-        self.cas_lock.store(false, Release);
+        (i, retry.failures())
     }
 
-    fn take(&mut self) -> BinaryHeap<T> {
-        let pq = unsafe { self.pq.get().as_mut() }.unwrap();
-        let new = BinaryHeap::with_capacity(pq.capacity());
-        std::mem::replace(pq, new)
-    }
+    /// Pop the maximum element in a priority subqueue.
+    ///
+    /// This works by trying to lock a random subqueue and popping an element
+    /// from that. Thus, this can have spurious [`None`]s when some subqueues
+    /// are empty while others are not. For a function that is guaranteed to
+    /// pop an element if any exist in any subqueues, see [`strong_pop()`].
+    ///
+    /// With [`with_length_weighted_pop()`], subqueues are picked in
+    /// proportion to their lengths instead of uniformly.
+    #[inline]
+    pub fn pop(&self) -> Option<T> {
+        let (t, failures) = self.with_rng(|rng| self.pop_sampled(rng));
+        self.contended(failures);
+        self.popped(t)
+    }
+
+    /// Like [`pop()`], but also returns how many subqueues it tried to lock.
+    ///
+    /// The count includes the successful try, so it's at least 1. Like
+    /// [`pop()`], this doesn't retry on an empty subqueue, so a count above 1
+    /// means subqueues were locked by other threads, and consistently high
+    /// counts mean heavy contention. Frequent [`None`]s with low counts mean
+    /// the structure is close to empty instead.
+    pub fn pop_with_attempts(&self) -> (Option<T>, usize) {
+        let (t, failures) = self.with_rng(|rng| self.pop_sampled(rng));
+        self.contended(failures);
+        (self.popped(t), failures + 1)
+    }
+
+    /// Like [`pop()`], but picks subqueues with `rng` instead of the
+    /// thread-local PRNG.
+    ///
+    /// With a seeded `rng` and no other threads, the sequence of subqueues
+    /// tried is reproducible.
+    pub fn sample_pop<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<T> {
+        let (t, failures) = self.pop_sampled(rng);
+        self.contended(failures);
+        self.popped(t)
+    }
+
+    /// Pops from subqueues picked by `rng` until one can be locked, and
+    /// returns what it held and how many tries failed.
+    #[inline]
+    fn pop_sampled<R: Rng + ?Sized>(&self, rng: &mut R) -> (Option<T>, usize) {
+        let mut i = match self.pop_index(rng) {
+            Some(i) => i,
+            None => self.sample_index(rng),
+        };
+        let retry = Retry::new();
+
+        loop {
+            if let Ok(t) = self.queues[i].try_pop() {
+                return (t, retry.failures());
+            }
+
+            i = match self.pop_index(rng) {
+                Some(i) => i,
+                None => self.resample(rng, i),
+            };
+            retry.wait();
+        }
+    }
+
+    /// Picks a subqueue for [`pop()`] weighted by length, if enabled.
+    ///
+    /// This is a linear walk over the lengths rather than a prebuilt
+    /// `WeightedIndex`, since the lengths change with every push and pop.
+    /// Returns [`None`] if weighting is off, or if every subqueue looked
+    /// empty.
+    fn pop_index<R: Rng + ?Sized>(&self, prng: &mut R) -> Option<usize> {
+        if !self.weighted_pop {
+            return None;
+        }
+
+        let total = self.queues.iter().map(Queue::len_relaxed).sum::<usize>();
+
+        if total == 0 {
+            return None;
+        }
+
+        let mut r = prng.gen_range(0, total);
+
+        for (i, queue) in self.queues.iter().enumerate() {
+            let len = queue.len_relaxed();
+
+            if r < len {
+                return Some(i);
+            }
+
+            r -= len;
+        }
+
+        // The lengths shrank between the two passes.
+        None
+    }
+
+    /// Push `t` into a subqueue and pop that subqueue's maximum, under a
+    /// single lock.
+    ///
+    /// The result is `t` itself if nothing in the chosen subqueue is greater,
+    /// in which case the subqueue isn't touched at all. Like [`pop()`], this
+    /// is the maximum of one random subqueue, not of the whole structure.
+    /// Since it always has `t` to fall back on, this never returns [`None`],
+    /// and it never changes [`len()`], so it doesn't wait on a bounded queue
+    /// that's full.
+    pub fn push_pop(&self, t: T) -> Option<T> {
+        match self.lock_random().heap().peek_mut() {
+            Some(mut top) if *top > t => Some(std::mem::replace(&mut *top, t)),
+            _ => Some(t),
+        }
+    }
+
+    /// Pop the maximum element of a subqueue and push `t` into it, under a
+    /// single lock, returning the old maximum.
+    ///
+    /// This is the other way around from [`push_pop()`]: the maximum is taken
+    /// *before* `t` goes in, so `t` is never handed straight back, even if
+    /// it's greater than everything in the subqueue. That's what a sliding
+    /// window wants, where the newest element must stay in. If the chosen
+    /// subqueue is empty, `t` is pushed into it and this returns [`None`].
+    /// The one exception is a bounded `self` that's full, where `t` can't go
+    /// in without something coming out: then the subqueues after the chosen
+    /// one are tried in turn until one has a maximum to replace. Since this
+    /// never waits for room, it doesn't hang on a full queue that no other
+    /// thread pops from. A queue bounded to 0 elements can't hold `t` at all,
+    /// so it's handed straight back.
+    pub fn replace_max(&self, t: T) -> Option<T> {
+        if self.bound == Some(0) {
+            return Some(t);
+        }
+
+        let n = self.queues.len();
+        let mut i = self.with_rng(|rng| self.sample_index(rng));
+        let retry = Retry::new();
+
+        loop {
+            if let Some(mut guard) = self.queues[i].try_lock() {
+                if let Some(mut top) = guard.heap().peek_mut() {
+                    return Some(std::mem::replace(&mut *top, t));
+                }
+
+                if self.reserve_room() {
+                    guard.heap().push(t);
+                    return None;
+                }
+            }
+
+            // Either it's locked, or `self` is full and so some other
+            // subqueue has an element to replace.
+            i = (i + 1) % n;
+            retry.wait();
+        }
+    }
+
+    /// Pop the maximum element in a priority subqueue, but only if `pred`
+    /// holds for it.
+    ///
+    /// Like [`pop()`], this locks a random subqueue, then peeks at its top
+    /// element and pops it only if `pred` returns `true`, leaving the subqueue
+    /// untouched otherwise. Note that this conditionally pops a *local*
+    /// maximum: the top of a random subqueue isn't necessarily the greatest
+    /// element overall, so a [`None`] doesn't mean `pred` is false for every
+    /// element, or even for the global maximum.
+    pub fn pop_if<F: Fn(&T) -> bool>(&self, pred: F) -> Option<T> {
+        let mut guard = self.lock_random();
+        let pq = guard.heap();
+
+        let popped = match pq.peek() {
+            Some(top) if pred(top) => pq.pop(),
+            _ => None,
+        };
+
+        drop(guard);
+        self.popped(popped)
+    }
+
+    /// Lock a random subqueue and look at its maximum element before deciding
+    /// whether to pop it.
+    ///
+    /// The returned [`PopGuard`] derefs to the maximum, and
+    /// [`PopGuard::commit()`] pops it. Dropping the guard instead leaves the
+    /// element where it was, so nothing has to be cloned to peek or pushed
+    /// back to cancel. Returns [`None`] if the chosen subqueue is empty, like
+    /// [`pop()`].
+    ///
+    /// The subqueue stays locked for as long as the guard lives, which keeps
+    /// other threads off it. Anything that waits for every subqueue's lock,
+    /// like [`peek_sorted()`], deadlocks if it's called by the thread holding
+    /// the guard, and with a single subqueue so does anything that locks one.
+    /// Keep the guard short-lived.
+    ///
+    /// [`peek_sorted()`]: MilkPQ::peek_sorted
+    pub fn pop_guard(&self) -> Option<PopGuard<'_, T, S, A>> {
+        let guard = self.lock_random();
+
+        if guard.heap_ref().is_empty() {
+            None
+        } else {
+            Some(PopGuard { mpq: self, guard })
+        }
+    }
+
+    /// Locks a random subqueue, retrying with others until one is free.
+    ///
+    /// The PRNG is released before returning, since callers run arbitrary
+    /// code under the lock.
+    fn lock_random(&self) -> LockGuard<'_, T, A> {
+        self.with_rng(|rng| {
+            let mut i = self.sample_index(rng);
+            let retry = Retry::new();
+
+            loop {
+                if let Some(guard) = self.queues[i].try_lock() {
+                    return guard;
+                }
+
+                i = self.resample(rng, i);
+                retry.wait();
+            }
+        })
+    }
+
+    /// Pop the maximum element in a priority subqueue, giving up after
+    /// `max_attempts` tries.
+    ///
+    /// Each attempt locks a random subqueue and pops from it. Unlike [`pop()`],
+    /// which retries until it gets a lock and then returns whatever that
+    /// subqueue had, this counts both failed locks *and* empty subqueues as
+    /// failed attempts and keeps sampling, returning [`None`] only once it
+    /// runs out of attempts. So it never spins indefinitely, even if every
+    /// subqueue is locked by other threads.
+    pub fn pop_or_none(&self, max_attempts: usize) -> Option<T> {
+        self.with_rng(|rng| {
+            for _ in 0..max_attempts {
+                let i = self.sample_index(rng);
+
+                if let Ok(Some(t)) = self.queues[i].try_pop() {
+                    return self.popped(Some(t));
+                }
+
+                spin();
+            }
+
+            None
+        })
+    }
+
+    /// Remove one element equal to `x`, returning whether there was one.
+    ///
+    /// Each subqueue is searched and, if it holds a match, has it removed
+    /// under a single lock, so there's no gap between finding `x` and
+    /// removing it in which another thread could remove it first: two
+    /// threads racing to remove the only copy of `x` can't both succeed.
+    /// This stops at the first subqueue with a match. It's `O(n)` in the
+    /// length of each subqueue it searches, plus a rebuild of the one it
+    /// removes from.
+    pub fn contains_and_remove(&self, x: &T) -> bool {
+        for queue in self.queues.as_ref() {
+            if queue.looks_empty() {
+                continue;
+            }
+
+            if let Some(t) = queue.remove(x) {
+                return self.popped(Some(t)).is_some();
+            }
+        }
+
+        false
+    }
+
+    /// Pop an element from the priority queue, but non-spuriously.
+    ///
+    /// This will check every subqueue until it finds some element (*not
+    /// necessarily the maximum in the whole structure*) and returns it, or
+    /// will return [`None`]. Thus, it returns [`None`] iff the structure is
+    /// truly empty.
+    ///
+    /// The scan starts at a random subqueue, so concurrent callers don't all
+    /// pile onto the first few. Subqueues that are locked are skipped without
+    /// even trying their lock, and only come back around once the rest of
+    /// the pass found nothing. Each later pass starts one subqueue further
+    /// along, and every time a subqueue is found locked again, this backs off
+    /// for as long as that subqueue's own backoff says, so a busy subqueue
+    /// slows the scan down without stalling it in place. Subqueues that were
+    /// empty as of their last change and aren't locked are passed over
+    /// without locking them at all, which saves most of the lock traffic on a
+    /// sparse structure.
+    pub fn strong_pop(&self) -> Option<T> {
+        let n = self.queues.len();
+        let mut start = self.with_rng(|rng| self.sample_index(rng));
+
+        if let Ok(t) = self.try_strong_pop_from(start, |_| ()) {
+            return t;
+        }
+
+        let retries = std::iter::repeat_with(Retry::new).take(n).collect::<Vec<_>>();
+
+        loop {
+            start = (start + 1) % n;
+
+            if let Ok(t) = self.try_strong_pop_from(start, |i| retries[i].wait()) {
+                return t;
+            }
+        }
+    }
+
+    /// Like [`strong_pop()`], but never waits on a locked subqueue.
+    ///
+    /// This makes a single pass over the subqueues, starting at a random one,
+    /// and pops from the first one that can be locked and isn't empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PopError::Locked`] if nothing was popped and some subqueue
+    /// was locked by another thread, since that subqueue may not have been
+    /// empty. So `Ok(None)` means the structure really was empty.
+    pub fn try_strong_pop(&self) -> Result<Option<T>, PopError> {
+        let start = self.with_rng(|rng| self.sample_index(rng));
+        self.try_strong_pop_from(start, |_| ()).map_err(|()| PopError::Locked)
+    }
+
+    /// Makes one pass over the subqueues from `start`, calling `locked` with
+    /// the index of each one that's skipped because it's locked.
+    ///
+    /// A subqueue that's locked is skipped on a plain load of its lock, since
+    /// a CAS on it would only fail and pull its cache line away from the
+    /// thread holding it.
+    fn try_strong_pop_from(&self, start: usize, mut locked: impl FnMut(usize)) -> Result<Option<T>, ()> {
+        let n = self.queues.len();
+        let mut skipped = false;
+
+        for i in (start..n).chain(0..start) {
+            let queue = &self.queues[i];
+
+            if queue.looks_empty() {
+                continue;
+            }
+
+            if !queue.raw_lock.is_locked() {
+                match queue.try_pop() {
+                    Ok(None) => continue,
+                    Ok(t) => return Ok(self.popped(t)),
+                    Err(()) => (),
+                }
+            }
+
+            skipped = true;
+            locked(i);
+        }
+
+        if skipped {
+            Err(())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Like [`strong_pop()`], but pops the maximum of the whole structure.
+    ///
+    /// This locks every subqueue at once, compares their tops, and pops the
+    /// greatest one before letting any of them go, so nothing can be pushed
+    /// past it or popped out from under it in between. Returns [`None`] iff
+    /// the structure is empty.
+    ///
+    /// That costs a lot more than a [`strong_pop()`]: it needs every
+    /// subqueue's lock, even the empty ones, and blocks all other operations
+    /// on `self` while it holds them, so it serializes every thread using
+    /// the queue. Use it for the rare pop that has to be exact.
+    ///
+    /// It never holds some locks while waiting for another. If any subqueue
+    /// is busy, it lets go of the ones it has, backs off, and tries the
+    /// whole set again, so other threads keep working between attempts and
+    /// the structure is only frozen for the pop itself.
+    pub fn strong_pop_global(&self) -> Option<T> {
+        let mut guards = self.lock_all();
+        let best = guards
+            .iter()
+            .enumerate()
+            .filter_map(|(i, guard)| guard.heap_ref().peek().map(|top| (i, top)))
+            .max_by(|(_, l), (_, r)| l.cmp(r))
+            .map(|(i, _)| i);
+        let t = best.and_then(|i| guards[i].heap().pop());
+        drop(guards);
+        self.popped(t)
+    }
+
+    /// Turns `self` into a descending sorted [`Vec`].
+    ///
+    /// This gathers every element and sorts them all at once. Sorting each
+    /// subqueue on its own and merging the runs sounds like it should win,
+    /// since each subqueue is already a heap, but it's about 1.2 to 3.7 times
+    /// slower for 1 to 64 subqueues; see `cargo bench --bench bench --
+    /// into_sorted_vec`. That includes a single subqueue, where
+    /// [`BinaryHeap::into_sorted_vec()`]'s heapsort also loses to sorting
+    /// the heap's buffer in place, which is what this does then.
+    ///
+    /// The order of elements that compare equal is unspecified, and can
+    /// differ between two queues holding the same elements in different
+    /// subqueues. If equal elements are still distinguishable and you need a
+    /// deterministic order, use [`into_sorted_vec_stable()`].
+    ///
+    /// [`into_sorted_vec_stable()`]: MilkPQ::into_sorted_vec_stable
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        self.into_sorted_vec_by(|l, r| l.cmp(r).reverse())
+    }
+
+    /// Turns `self` and `other` into one descending sorted [`Vec`].
+    ///
+    /// Each is sorted on its own and the two results are then merged in
+    /// linear time, which beats sorting everything together.
+    pub fn merge_sorted_into_vec(self, other: Self) -> Vec<T> {
+        let (left, right) = (self.into_sorted_vec(), other.into_sorted_vec());
+        let mut vec = Vec::with_capacity(left.len() + right.len());
+        let mut left = left.into_iter().peekable();
+        let mut right = right.into_iter().peekable();
+
+        loop {
+            let next = match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) if l >= r => left.next(),
+                (Some(_), Some(_)) => right.next(),
+                _ => break,
+            };
+
+            vec.extend(next);
+        }
+
+        vec.extend(left);
+        vec.extend(right);
+        vec
+    }
+
+    /// Like [`into_sorted_vec()`], but gives up on sorting if `deadline` has
+    /// passed by the time everything is drained.
+    ///
+    /// The sort itself can't be interrupted, so this only checks the
+    /// deadline once the elements have been gathered up, right before
+    /// sorting. Past that point it'll run to completion however long the
+    /// sort takes.
+    ///
+    /// # Errors
+    ///
+    /// If the deadline has passed, returns every element unsorted.
+    pub fn into_sorted_vec_deadline(self, deadline: Instant) -> Result<Vec<T>, Vec<T>> {
+        let mut vec = Vec::from(self);
+
+        if Instant::now() >= deadline {
+            Err(vec)
+        } else {
+            vec.sort_unstable_by(|l, r| l.cmp(r).reverse());
+            Ok(vec)
+        }
+    }
+
+    /// Like [`into_sorted_vec()`], but sorts into `buf` instead of a new
+    /// [`Vec`].
+    ///
+    /// `buf` is cleared first, so its old contents are dropped but its
+    /// allocation is reused, which saves allocating a fresh [`Vec`] every time
+    /// in loops that sort repeatedly.
+    pub fn into_sorted_into(mut self, buf: &mut Vec<T>) {
+        buf.clear();
+        self.drain_into(buf);
+        buf.sort_unstable_by(|l, r| l.cmp(r).reverse());
+    }
+
+    /// Turns `self` into a [`Vec`] sorted in ascending order by `cmp`.
+    ///
+    /// [`into_sorted_vec()`] is this with `cmp` being the reverse of
+    /// [`Ord::cmp`].
+    pub fn into_sorted_vec_by<F: FnMut(&T, &T) -> CmpOrdering>(self, cmp: F) -> Vec<T> {
+        let mut vec = Vec::from(self);
+        // Unstable, so equal elements end up in whatever order the sort
+        // leaves them, on top of whichever subqueues they happened to be in.
+        // `into_sorted_vec_stable()` is for callers who care.
+        vec.sort_unstable_by(cmp);
+        vec
+    }
+
+    /// Like [`into_sorted_vec()`], but equal elements keep their relative
+    /// order.
+    ///
+    /// Note that this only means the order they come out of the subqueues in
+    /// (the order of [`Vec::from`]), which already has little to do with the
+    /// order they were pushed in, since pushes are scattered across random
+    /// subqueues and each subqueue is a heap. It's for when equal elements
+    /// are distinguishable and you need a deterministic result from a given
+    /// state, not for recovering insertion order.
+    pub fn into_sorted_vec_stable(self) -> Vec<T> {
+        let mut vec = Vec::from(self);
+        vec.sort_by(|l, r| l.cmp(r).reverse());
+        vec
+    }
+
+    /// Change the number of subqueues, redistributing the elements evenly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0, or if there isn't memory for `limit`
+    /// subqueues, in which case `self` is left as it was.
+    pub fn resize_queues(&mut self, limit: usize) {
+        assert!(limit > 0, "a MilkPQ needs at least one subqueue");
+        // Elements are dealt out round-robin, so this is exactly enough room.
+        let cap = self.len().div_ceil(limit);
+        let mut bufs = Vec::new();
+        let reserved = bufs.try_reserve_exact(limit);
+        assert!(reserved.is_ok(), "can't allocate {} subqueues", limit);
+        let first = self.queues[0].pq.get_mut();
+        bufs.extend(std::iter::repeat_with(|| allocator::buf_like::<T, A>(first, cap)).take(limit));
+        let elems = self.queues.iter_mut().flat_map(|queue| allocator::take::<T, A>(queue.pq.get_mut()).into_vec());
+
+        for (i, t) in elems.enumerate() {
+            bufs[i % limit].push(t);
+        }
+
+        // Heapifying each buffer at once is `O(n)`, unlike pushing into heaps.
+        let queues = bufs.into_iter().map(|buf| Queue::new(BinaryHeap::from(buf))).collect();
+        self.replace_queues(queues);
+    }
+
+    /// Swap in a new set of subqueues, which may be a different number of
+    /// them.
+    ///
+    /// Anything that changes the number of subqueues goes through here, so
+    /// that the distribution for picking them always matches.
+    fn replace_queues(&mut self, queues: Box<[Queue<T, A>]>) {
+        self.dist = dist_for(queues.len());
+        self.queues = queues;
+        self.recount();
+    }
+
+    /// The lock contention counters summed over every subqueue, since
+    /// creation or the last [`reset_stats()`].
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        self.queues.iter().fold(Stats::default(), |stats, queue| Stats {
+            lock_attempts: stats.lock_attempts + queue.attempts.load(order::COUNT),
+            lock_failures: stats.lock_failures + queue.failures.load(order::COUNT),
+        })
+    }
+
+    /// Count the subqueues by length, in `buckets` equal ranges between the
+    /// shortest and longest subqueue.
+    ///
+    /// Each subqueue is locked once to read its length. If every subqueue is
+    /// the same length they all land in the first bucket, and the more they
+    /// spread out over the rest, the less balanced `self` is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buckets` is 0.
+    #[must_use]
+    pub fn len_histogram(&self, buckets: usize) -> Vec<usize> {
+        assert!(buckets > 0, "can't make a histogram with 0 buckets");
+        let lens = self.queues.iter().map(|queue| queue.with_locked(|pq| pq.len())).collect::<Vec<_>>();
+        let min = lens.iter().copied().min().unwrap_or(0);
+        let span = lens.iter().copied().max().unwrap_or(0) - min + 1;
+        let mut histogram = vec![0; buckets];
+
+        for len in lens {
+            // Widened so that a huge subqueue times many buckets can't
+            // overflow.
+            let bucket = (len - min) as u128 * buckets as u128 / span as u128;
+            histogram[bucket as usize] += 1;
+        }
+
+        histogram
+    }
+
+    /// Zero the counters reported by [`stats()`].
+    pub fn reset_stats(&self) {
+        for queue in self.queues.as_ref() {
+            queue.attempts.store(0, order::COUNT);
+            queue.failures.store(0, order::COUNT);
+        }
+    }
+
+    /// Make [`pop()`] pick subqueues weighted by their lengths.
+    ///
+    /// Uniform sampling often lands on empty subqueues when most elements
+    /// sit in a few of them, which is when [`pop()`] returns a spurious
+    /// [`None`]. Weighting by length means a pop only picks an empty
+    /// subqueue if the lengths it read were stale, at the cost of reading
+    /// every subqueue's length on each pop.
+    #[must_use]
+    pub fn with_length_weighted_pop(mut self) -> Self {
+        self.weighted_pop = true;
+        self
+    }
+
+    /// Choose whether to keep a shared counter of the number of elements.
+    ///
+    /// With the counter, which is the default, [`len()`] is a single atomic
+    /// load, but every [`push()`] and [`pop()`] also updates the counter,
+    /// which all threads contend on. Without it, pushes and pops only touch
+    /// their own subqueue, and [`len()`] instead sums the lengths of every
+    /// subqueue, which is `O(num_queues())`. Either way, [`len()`] is exact
+    /// whenever no other thread is pushing or popping.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `track_len` is `false` and `self` is bounded, since the
+    /// bound is enforced with the counter.
+    #[must_use]
+    pub fn with_track_len(mut self, track_len: bool) -> Self {
+        assert!(track_len || self.bound.is_none(), "a bounded MilkPQ has to track its length");
+        self.track_len = track_len;
+        self.recount();
+        self
+    }
+
+    /// Call `f` whenever a [`push()`] or [`pop()`] fails to lock more than
+    /// `threshold` subqueues before succeeding, with the number of failures.
+    ///
+    /// This is a hook for feeding your own autoscaling or alerting, without
+    /// this crate depending on any particular metrics library. `f` runs on
+    /// the thread that hit the contention, after the operation is done, so it
+    /// should be cheap. Setting a new callback replaces the old one.
+    pub fn set_contention_callback<F>(&self, threshold: usize, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let mut contention = self.contention.write().unwrap_or_else(PoisonError::into_inner);
+        *contention = Some(Arc::new(f));
+        self.contention_threshold.store(threshold, order::SETTING);
+    }
+
+    /// Remove the callback set by [`set_contention_callback()`].
+    pub fn clear_contention_callback(&self) {
+        let mut contention = self.contention.write().unwrap_or_else(PoisonError::into_inner);
+        *contention = None;
+        self.contention_threshold.store(usize::MAX, order::SETTING);
+    }
+
+    fn contention_callback(&self) -> Option<ContentionCallback> {
+        self.contention.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Reports `failures` failed lock attempts to the contention callback,
+    /// if there is one and it's over the threshold.
+    fn contended(&self, failures: usize) {
+        if failures > self.contention_threshold.load(order::SETTING) {
+            if let Some(f) = self.contention_callback() {
+                f(failures);
+            }
+        }
+    }
+
+    /// Set the lock failure rate that [`auto_tune()`] aims for.
+    pub fn set_tune_target(&mut self, rate: f64) {
+        self.tune_target = rate;
+    }
+
+    /// Grow or shrink the subqueue count based on how contended the locks
+    /// have been, then reset the [`stats()`].
+    ///
+    /// If more than the [target](MilkPQ::set_tune_target()) fraction of lock
+    /// attempts failed since the last call, the subqueue count is doubled.
+    /// If fewer than a quarter of the target did, it's halved, down to one
+    /// subqueue. Nothing changes if there were too few attempts to tell.
+    /// This is meant to be called periodically, e.g. from a maintenance
+    /// thread, so that the structure keeps up with the number of threads
+    /// using it.
+    pub fn auto_tune(&mut self) {
+        let stats = self.stats();
+
+        if stats.lock_attempts < TUNE_MIN_ATTEMPTS {
+            return;
+        }
+
+        let rate = stats.failure_rate();
+        let n = self.queues.len();
+
+        if rate > self.tune_target && n < MAX_QUEUES {
+            self.resize_queues((n * 2).min(MAX_QUEUES));
+        } else if rate < self.tune_target / 4.0 && n > 1 {
+            self.resize_queues(n / 2);
+        }
+
+        self.reset_stats();
+    }
+
+    /// Combine `self` and `other` into one queue.
+    ///
+    /// The result keeps the subqueue count and settings of `self`. If both
+    /// have the same number of subqueues, corresponding subqueues are merged
+    /// together, otherwise the elements of `other` are spread evenly across
+    /// the subqueues of `self`. No locking is needed since both are owned.
+    ///
+    /// If `self` is bounded, the result may be over its bound, in which case
+    /// it's [full](MilkPQ::is_full()) until enough elements are popped.
+    #[must_use]
+    pub fn merge(mut self, other: Self) -> Self {
+        if self.queues.len() == other.queues.len() {
+            for (queue, other) in self.queues.iter_mut().zip(other.queues.into_vec()) {
+                queue.pq.get_mut().append(&mut other.pq.into_inner());
+                *queue.len.get_mut() = queue.pq.get_mut().len();
+            }
+        } else {
+            let mut rest = Vec::from(other);
+            let per_queue = rest.len() / self.queues.len() + 1;
+
+            for queue in self.queues.as_mut() {
+                let at = rest.len().saturating_sub(per_queue);
+                // Extending a heap rebuilds it in `O(n)` if that beats
+                // pushing one at a time, same as appending does.
+                queue.pq.get_mut().extend(rest.drain(at..));
+                *queue.len.get_mut() = queue.pq.get_mut().len();
+            }
+        }
+
+        self.recount();
+        self
+    }
+
+    /// Keep only the elements for which `f` returns `true`.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        for queue in self.queues.as_mut() {
+            queue.retain(&mut f);
+        }
+
+        self.recount();
+    }
+
+    /// Like [`retain()`], but `f` is also passed the index of the subqueue
+    /// each element is in.
+    ///
+    /// This is meant for diagnostics, like building a histogram of how the
+    /// elements are spread between subqueues while filtering them.
+    pub fn retain_with_index<F: FnMut(usize, &T) -> bool>(&mut self, mut f: F) {
+        for (i, queue) in self.queues.iter_mut().enumerate() {
+            queue.retain(|t| f(i, t));
+        }
+
+        self.recount();
+    }
+
+    /// Apply `f` to every element, which may change its priority.
+    ///
+    /// Changing an element in place could break its heap's order, so each
+    /// subqueue's heap is taken apart into its buffer, `f` is run on every
+    /// element, and the buffer is heapified again in `O(n)`. The buffers are
+    /// reused, so nothing is allocated. This is for bulk priority updates,
+    /// like decaying every priority at once.
+    ///
+    /// If `f` panics, the subqueue it was working on loses its elements, but
+    /// [`len()`] stays accurate.
+    pub fn update_each<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        for heap in self.heaps_mut() {
+            let mut vec = allocator::take::<T, A>(heap.heap).into_vec();
+            vec.iter_mut().for_each(&mut f);
+            *heap.heap = BinaryHeap::from(vec);
+        }
+    }
+
+    /// Remove and return the elements for which `f` returns `true`.
+    pub fn drain_filter<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> Vec<T> {
+        let mut vec = Vec::new();
+
+        for queue in self.queues.as_mut() {
+            vec.append(&mut queue.drain_filter(&mut f));
+        }
+
+        self.recount();
+        vec
+    }
+
+    /// Mutably borrow each subqueue's [`BinaryHeap`] directly.
+    ///
+    /// This is an escape hatch for bulk operations this crate doesn't
+    /// provide. It's safe because `&mut self` means no other thread can be
+    /// using the subqueues, and the length and capacity counters are fixed up
+    /// as each [`HeapMut`] is dropped. Forgetting one instead leaves those
+    /// counters stale, though that can't cause anything worse than a wrong
+    /// [`len()`].
+    ///
+    /// ```
+    /// # use milkpq::MilkPQ;
+    /// let mut pq = (0..100).collect::<MilkPQ<i32>>();
+    ///
+    /// // Double every priority and drop the odd ones, in place.
+    /// for mut heap in pq.heaps_mut() {
+    ///     let doubled = std::mem::take(&mut *heap).into_iter().map(|t| t * 2);
+    ///     heap.extend(doubled.filter(|t| t % 4 == 0));
+    /// }
+    ///
+    /// assert_eq!(pq.len(), 50);
+    /// assert_eq!(pq.into_sorted_vec()[0], 196);
+    /// ```
+    pub fn heaps_mut(&mut self) -> impl Iterator<Item = HeapMut<'_, T, A>> {
+        let total_len = &self.len;
+        let total_capacity = &*self.capacity;
+
+        self.queues.iter_mut().map(move |queue| HeapMut {
+            heap: queue.pq.get_mut(),
+            len: queue.len.get_mut(),
+            capacity: queue.capacity.get_mut(),
+            total_len,
+            total_capacity,
+        })
+    }
+
+    /// Resets the length and capacity counters from the subqueues, and makes
+    /// sure every subqueue reports its capacity changes to `self`.
+    fn recount(&mut self) {
+        *self.len.get_mut() = self.queues.iter_mut().map(|q| *q.len.get_mut()).sum();
+        // Saturating, since every subqueue of a zero-sized `T` has a capacity
+        // of `usize::MAX`.
+        let capacity = self.queues
+            .iter_mut()
+            .map(|q| q.pq.get_mut().capacity())
+            .fold(0, usize::saturating_add);
+        self.capacity.store(capacity, order::COUNT);
+
+        for queue in self.queues.as_mut() {
+            *queue.capacity.get_mut() = queue.pq.get_mut().capacity();
+            queue.total_capacity = Arc::clone(&self.capacity);
+        }
+    }
+
+    /// Clears all subqueues in the structure.
+    ///
+    /// This keeps the subqueues' capacity around for reuse. See
+    /// [`clear_and_shrink()`] to free it instead.
+    ///
+    /// If [`len()`] is zero, this returns straight away without locking any
+    /// subqueues.
+    pub fn clear(&self) {
+        if self.is_empty() {
+            return;
+        }
+
+        for queue in self.queues.as_ref() {
+            let cleared = queue.clear();
+
+            if self.track_len {
+                self.len.fetch_sub(cleared, order::COUNT);
+            }
+        }
+    }
+
+    /// Clears all subqueues in the structure and frees their memory.
+    ///
+    /// Each subqueue's heap is swapped for an empty one with no capacity, and
+    /// the old one is dropped after unlocking, so the elements' destructors
+    /// don't run under the lock.
+    pub fn clear_and_shrink(&self) {
+        for queue in self.queues.as_ref() {
+            let old = queue.with_locked(allocator::take::<T, A>);
+
+            if self.track_len {
+                self.len.fetch_sub(old.len(), order::COUNT);
+            }
+        }
+    }
+
+    /// Shrink the capacity of the structure with a lower bound.
+    ///
+    /// The `min_capacity` is split evenly between the subqueues, so each one
+    /// keeps at least `min_capacity / num_queues()` capacity around.
+    pub fn shrink_to(&self, min_capacity: usize) {
+        let min_capacity = min_capacity / self.queues.len();
+
+        for queue in self.queues.as_ref() {
+            queue.shrink_to(min_capacity);
+        }
+    }
+
+    /// Reserve capacity for at least `additional` more elements.
+    ///
+    /// Like [`shrink_to()`], `additional` is split evenly between the
+    /// subqueues, rounding up. A subqueue that already has that much room,
+    /// going by the capacity and length it had when it was last unlocked, is
+    /// passed over without locking it, so this is cheap on a queue that's
+    /// already big enough. Other threads can use up the room as soon as it's
+    /// made, so this is only a hint under concurrency.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a subqueue's new capacity overflows `usize`.
+    ///
+    /// [`shrink_to()`]: MilkPQ::shrink_to
+    pub fn reserve(&self, additional: usize) {
+        let additional = additional.div_ceil(self.queues.len());
+
+        for queue in self.queues.as_ref() {
+            queue.reserve(additional);
+        }
+    }
+
+    /// Like [`reserve()`](MilkPQ::reserve), but doesn't over-allocate, like
+    /// [`BinaryHeap::reserve_exact()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if a subqueue's new capacity overflows `usize`.
+    pub fn reserve_exact(&self, additional: usize) {
+        let additional = additional.div_ceil(self.queues.len());
+
+        for queue in self.queues.as_ref() {
+            queue.reserve_exact(additional);
+        }
+    }
+
+    /// Empty the contents of `self` into a [`Vec`] and leave `self` empty.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut vec = Vec::new();
+        self.drain_into(&mut vec);
+        vec
+    }
+
+    /// Empty the contents of `self` onto the end of `dst` and leave `self`
+    /// empty.
+    ///
+    /// This is [`drain()`] without allocating a new [`Vec`] every time.
+    pub fn drain_into(&mut self, dst: &mut Vec<T>) {
+        dst.reserve(self.len());
+
+        for queue in self.queues.as_mut() {
+            dst.extend(queue.take());
+        }
+
+        *self.len.get_mut() = 0;
+    }
+
+    /// Empty the contents of `self` into `n` [`Vec`]s of nearly equal length,
+    /// leaving `self` empty.
+    ///
+    /// The lengths differ by at most one, with the longer ones first, so each
+    /// can be moved to its own worker thread. The elements aren't in any
+    /// particular order, within a chunk or between them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn split_drain(&mut self, n: usize) -> Vec<Vec<T>> {
+        assert!(n > 0, "can't split into 0 chunks");
+        let len = self.len();
+        let (size, extra) = (len / n, len % n);
+        let target = |i: usize| size + usize::from(i < extra);
+        let mut chunks = (0..n).map(|i| Vec::with_capacity(target(i))).collect::<Vec<_>>();
+        let mut i = 0;
+
+        for queue in self.queues.as_mut() {
+            for t in queue.take().into_vec() {
+                if chunks[i].len() == target(i) && i + 1 < n {
+                    i += 1;
+                }
+
+                chunks[i].push(t);
+            }
+        }
+
+        *self.len.get_mut() = 0;
+        chunks
+    }
+
+    /// Empty the contents of `self` into a descending sorted [`Vec`], leaving
+    /// `self` empty.
+    ///
+    /// This is [`into_sorted_vec()`] for when you want to keep using `self`
+    /// afterwards.
+    pub fn drain_sorted(&mut self) -> Vec<T> {
+        let mut vec = self.drain();
+        vec.sort_unstable_by(|l, r| l.cmp(r).reverse());
+        vec
+    }
+
+    /// Empty the contents of `self` into a [`Vec`] without needing `&mut`.
+    ///
+    /// Each subqueue is locked and emptied in turn, so every subqueue is
+    /// drained atomically, but the structure as a whole isn't: elements
+    /// pushed into an already-drained subqueue while this runs stay behind.
+    pub fn drain_concurrent(&self) -> Vec<T> {
+        let mut vec = Vec::new();
+
+        for queue in self.queues.as_ref() {
+            let heap = queue.lock_take();
+
+            if self.track_len {
+                self.len.fetch_sub(heap.len(), order::COUNT);
+            }
+
+            vec.extend(heap);
+        }
+
+        vec
+    }
+
+    /// Pop every element in the structure without needing `&mut`.
+    ///
+    /// This is [`drain_concurrent()`] under the name you'd look for on a
+    /// shutdown path, where `self` is shared behind an [`Arc`] and can't be
+    /// unwrapped. Elements pushed concurrently may or may not be captured,
+    /// depending on whether their subqueue was already emptied.
+    pub fn pop_all(&self) -> Vec<T> {
+        self.drain_concurrent()
+    }
+
+    /// Extend `self` using an [`IntoIterator`].
+    ///
+    /// Exactly like [`Extend`], except it doesn't mutably borrow `self`.
+    ///
+    /// Rather than pushing one element at a time, this deals chunks of `iter`
+    /// out round-robin into per-subqueue buckets, starting from a random
+    /// subqueue, and pushes each bucket under a single lock. So it takes
+    /// about one lock per subqueue per chunk instead of one per element,
+    /// samples the PRNG once per call instead of once per element, and
+    /// leaves the subqueues' lengths within one of each other. Bounded
+    /// queues still push one at a time so that they can wait for room.
+    pub fn extend_ref<I: IntoIterator<Item = T>>(&self, iter: I) {
+        let mut iter = iter.into_iter();
+
+        if self.bound.is_some() {
+            for t in iter {
+                self.push(t);
+            }
+
+            return;
+        }
+
+        let mut buckets = std::iter::repeat_with(Vec::new)
+            .take(self.queues.len())
+            .collect::<Vec<_>>();
+        let chunk = EXTEND_CHUNK_PER_QUEUE * self.queues.len();
+        let mut next = self.with_rng(|rng| self.sample_index(rng));
+
+        loop {
+            let mut taken = 0;
+
+            for t in iter.by_ref().take(chunk) {
+                buckets[next].push(t);
+                next = (next + 1) % buckets.len();
+                taken += 1;
+            }
+
+            if taken == 0 {
+                break;
+            }
+
+            if self.track_len {
+                self.len.fetch_add(taken, order::COUNT);
+            }
+
+            for (queue, bucket) in self.queues.iter().zip(&mut buckets) {
+                if !bucket.is_empty() {
+                    queue.extend(bucket.drain(..));
+                }
+            }
+        }
+    }
+
+    /// The number of elements in the structure.
+    ///
+    /// This is read from a counter kept alongside the subqueues, so it's
+    /// exact whenever no other thread is pushing or popping, and otherwise
+    /// may be briefly off by the operations in flight.
+    ///
+    /// If `self` was built [`with_track_len(false)`](Self::with_track_len),
+    /// this sums the lengths of the subqueues instead.
+    ///
+    /// Like the other queries, ignoring the result is a warning:
+    ///
+    /// ```compile_fail
+    /// #![deny(unused_must_use)]
+    /// milkpq::MilkPQ::<i32>::new().len();
+    /// ```
+    #[must_use]
+    pub fn len(&self) -> usize {
+        if self.track_len {
+            self.len.load(order::COUNT)
+        } else {
+            self.queues.iter().map(Queue::len_relaxed).sum()
+        }
+    }
+
+    /// The number of elements in the structure, in `O(1)`.
+    ///
+    /// This is the same as [`len()`], named to go along with
+    /// [`total_capacity()`].
+    #[must_use]
+    pub fn total_len(&self) -> usize {
+        self.len()
+    }
+
+    /// The sum of the capacities of all subqueues, in `O(1)` and without
+    /// locking.
+    ///
+    /// Each subqueue adds its change in capacity to a shared counter when it
+    /// unlocks after reallocating, so this is eventually consistent: it's
+    /// exact once no other thread is pushing or shrinking, and otherwise may
+    /// lag behind reallocations still in flight. For zero-sized `T`, which
+    /// never needs to allocate, this is always [`usize::MAX`].
+    #[must_use]
+    pub fn total_capacity(&self) -> usize {
+        self.capacity.load(order::COUNT)
+    }
+
+    /// Estimate [`len()`] from a few random subqueues, without locking.
+    ///
+    /// This reads the lengths of [`LEN_APPROX_SAMPLES`] randomly picked
+    /// subqueues and scales their sum up to the whole structure, so it never
+    /// touches the shared length counter. The estimate is exact when the
+    /// elements are spread evenly between the subqueues, and otherwise has a
+    /// relative error of about `cv / sqrt(LEN_APPROX_SAMPLES)`, where `cv` is
+    /// the standard deviation of the subqueue lengths over their mean. It's
+    /// meant for monitoring, not for making decisions about the queue.
+    #[must_use]
+    pub fn len_approx(&self) -> usize {
+        let sampled = self.with_rng(|rng| {
+            (0..LEN_APPROX_SAMPLES)
+                .map(|_| self.queues[self.sample_index(rng)].len_relaxed())
+                .sum::<usize>()
+        });
+
+        sampled * self.queues.len() / LEN_APPROX_SAMPLES
+    }
+
+    /// Whether [`len()`] is zero.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `self` is bounded and [`len()`] has reached the bound.
+    ///
+    /// This is always `false` for unbounded queues.
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.bound.is_some_and(|bound| self.len() >= bound)
+    }
+
+    /// A [`Debug`] view of `self` that waits for every subqueue's lock.
+    ///
+    /// The [`Debug`] impl of [`MilkPQ`] itself skips subqueues that are
+    /// locked, so that it's safe to log from anywhere. This one gives the
+    /// complete contents instead, but it can block on busy subqueues.
+    #[must_use]
+    pub fn debug_full(&self) -> DebugFull<'_, T, S, A> {
+        DebugFull(self)
+    }
+
+    /// The number of subqueues in the structure.
+    #[must_use]
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Pop the maximum element of the `i`th subqueue.
+    ///
+    /// Unlike [`pop()`], this waits until it can lock that exact subqueue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` isn't less than [`num_queues()`].
+    pub fn pop_at(&self, i: usize) -> Option<T> {
+        self.popped(self.queue_at(i).pop())
+    }
+
+    /// Try to pop the maximum element of the `i`th subqueue, without waiting.
+    ///
+    /// This makes a single attempt at the subqueue's lock. It's the building
+    /// block for schedulers that want their own scanning or backoff over
+    /// specific subqueues.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PopError::Locked`] if the subqueue was locked by another
+    /// thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` isn't less than [`num_queues()`].
+    pub fn try_pop_at(&self, i: usize) -> Result<Option<T>, PopError> {
+        match self.queue_at(i).try_pop() {
+            Ok(t) => Ok(self.popped(t)),
+            Err(()) => Err(PopError::Locked),
+        }
+    }
+
+    /// Push an element into the `i`th subqueue.
+    ///
+    /// Unlike [`push()`], this waits until it can lock that exact subqueue,
+    /// bypassing the random load-balancing. This is useful for sharding
+    /// related elements into the same subqueue so they can be drained together
+    /// with [`pop_at()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` isn't less than [`num_queues()`].
+    pub fn push_to(&self, i: usize, t: T) {
+        let queue = self.queue_at(i);
+
+        while !self.reserve_room() {
+            spin();
+        }
+
+        queue.push(t);
+    }
+
+    fn queue_at(&self, i: usize) -> &Queue<T, A> {
+        let len = self.queues.len();
+        assert!(i < len, "subqueue index {} out of range for {} subqueues", i, len);
+        &self.queues[i]
+    }
+
+    /// Runs `f` with this thread's PRNG for `self`: the seeded one if `self`
+    /// was built with [`with_seed_and_queues()`], otherwise the thread-local
+    /// one.
+    ///
+    /// The PRNG is borrowed for the whole call, so `f` mustn't call back into
+    /// `with_rng()`.
+    #[inline]
+    fn with_rng<R>(&self, f: impl FnOnce(&mut SmallRng) -> R) -> R {
+        match &self.seeded {
+            None => f(&mut PRNG.borrow_mut()),
+            Some(seeded) => {
+                let mut rng = seeded.take();
+                let r = f(&mut rng);
+                seeded.put_back(rng);
+                r
+            }
+        }
+    }
+
+    /// Picks a random subqueue, without touching `rng` if there's only one.
+    #[inline]
+    fn sample_index<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        self.dist.map_or(0, |dist| rng.sample(dist))
+    }
+
+    /// Picks another random subqueue after failing to lock the `failed`th,
+    /// never picking `failed` again straight away.
+    #[inline]
+    fn resample<R: Rng + ?Sized>(&self, prng: &mut R, failed: usize) -> usize {
+        let i = self.sample_index(prng);
+
+        if i == failed {
+            (i + 1) % self.queues.len()
+        } else {
+            i
+        }
+    }
+
+    /// Claims room for one more element, failing if `self` is full.
+    #[inline]
+    fn reserve_room(&self) -> bool {
+        match self.bound {
+            None if !self.track_len => true,
+            None => {
+                self.len.fetch_add(1, order::COUNT);
+                true
+            }
+            Some(bound) => self.len
+                .fetch_update(order::COUNT, order::COUNT, |len| if len < bound { Some(len + 1) } else { None })
+                .is_ok(),
+        }
+    }
+
+    /// Lock every subqueue and hold all the locks until the guards are
+    /// dropped. The guards are in index order.
+    ///
+    /// # Lock strategy
+    ///
+    /// This never waits on a lock while holding another one. Waiting for
+    /// each subqueue in turn would keep every subqueue before a busy one
+    /// locked the whole time it waited, freezing most of the structure
+    /// behind one slow holder. Instead, each round waits for a single
+    /// subqueue with nothing else held, then only *tries* to lock the rest,
+    /// and if any of them is busy, lets go of everything, backs off, and
+    /// starts the next round by waiting on the one that was busy. The whole
+    /// structure is only ever frozen once every lock is in hand.
+    ///
+    /// Since nothing waits while holding a lock, this can't be part of a
+    /// deadlock with other callers, or with anything that holds one lock at
+    /// a time, like `Clone` and [`clear()`].
+    fn lock_all(&self) -> Vec<LockGuard<'_, T, A>> {
+        let retry = Retry::new();
+        let mut busy = 0;
+
+        loop {
+            let mut guards = std::iter::repeat_with(|| None).take(self.queues.len()).collect::<Vec<_>>();
+            guards[busy] = Some(self.queues[busy].lock());
+            let failed = self
+                .queues
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != busy)
+                .find(|&(i, queue)| {
+                    guards[i] = queue.try_lock();
+                    guards[i].is_none()
+                });
+
+            match failed {
+                Some((i, _)) => busy = i,
+                None => return guards.into_iter().flatten().collect(),
+            }
+
+            drop(guards);
+            retry.wait();
+        }
+    }
+
+    /// Gives back the room taken by a popped element.
+    #[inline]
+    fn popped(&self, t: Option<T>) -> Option<T> {
+        if t.is_some() && self.track_len {
+            self.len.fetch_sub(1, order::COUNT);
+        }
+
+        t
+    }
+}
+
+impl<T: Ord, S: BuildHasher, A: Allocator + Clone> MilkPQ<T, S, A> {
+    /// Push an element into the subqueue picked by hashing `key`.
+    ///
+    /// Elements pushed with equal keys land in the same subqueue, unless it's
+    /// locked, in which case the next subqueues are tried in turn. This keeps
+    /// related elements together without giving up on the multiqueue.
+    pub fn push_keyed<K: Hash + ?Sized>(&self, key: &K, mut t: T) {
+        while !self.reserve_room() {
+            spin();
+        }
+
+        // Truncating the hash is fine, it only has to pick a subqueue.
+        #[allow(clippy::cast_possible_truncation)]
+        let mut i = self.hasher.hash_one(key) as usize % self.queues.len();
+
+        while let Err(t2) = self.queues[i].try_push(t) {
+            t = t2;
+            i = (i + 1) % self.queues.len();
+            spin();
+        }
+    }
+}
+
+impl<T: Ord, S: Clone, A: Allocator + Clone> MilkPQ<T, S, A> {
+    /// Builds a clone of `self` around already-cloned subqueues.
+    fn with_cloned_queues(&self, queues: Box<[Queue<T, A>]>) -> Self {
+        let mut pq = MilkPQ {
+            queues,
+            dist: self.dist,
+            hasher: self.hasher.clone(),
+            len: AtomicUsize::new(0),
+            track_len: self.track_len,
+            capacity: Arc::default(),
+            bound: self.bound,
+            tune_target: self.tune_target,
+            weighted_pop: self.weighted_pop,
+            seeded: self.seeded.as_ref().map(|seeded| SeededRngs::new(seeded.seed)),
+            contention: RwLock::new(self.contention_callback()),
+            contention_threshold: AtomicUsize::new(self.contention_threshold.load(order::SETTING)),
+        };
+
+        pq.recount();
+        pq
+    }
+}
+
+impl<T: Ord + Clone, S: Clone, A: Allocator + Clone> MilkPQ<T, S, A> {
+    /// Clone `self`, keeping the capacity of each subqueue.
+    ///
+    /// [`Clone`] only guarantees room for the elements themselves, so this is
+    /// the one to use for cloning a pre-reserved template queue.
+    #[must_use]
+    pub fn clone_with_capacity(&self) -> Self {
+        let queues = self.queues
+            .iter()
+            .map(Queue::clone_with_capacity)
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+
+        self.with_cloned_queues(queues)
+    }
+}
+
+impl<T: Ord + Clone, S, A: Allocator + Clone> MilkPQ<T, S, A> {
+    /// Clone the minimum element in the whole structure.
+    ///
+    /// This is what a bounded top-N set needs to find its eviction candidate.
+    /// The subqueues are max-heaps, so this has to lock every subqueue at
+    /// once and scan the leaves of each heap, which is `O(n)` and blocks all
+    /// other operations while it runs. Use it sparingly.
+    #[must_use]
+    pub fn peek_global_min(&self) -> Option<T> {
+        let mut guards = self.lock_all();
+
+        guards
+            .iter_mut()
+            .filter_map(|guard| {
+                let pq = guard.heap();
+                // The minimum of a max-heap is always one of its leaves.
+                pq.as_slice()[pq.len() / 2..].iter().min()
+            })
+            .min()
+            .cloned()
+    }
+
+    /// Clone the maximum element of the `i`th subqueue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` isn't less than [`num_queues()`].
+    #[must_use]
+    pub fn peek_at(&self, i: usize) -> Option<T> {
+        self.queue_at(i).peek()
+    }
+
+    /// Clone the greatest of the subqueues' maximums, locking one subqueue
+    /// at a time.
+    ///
+    /// This is approximate: subqueues can change between visits, so the
+    /// result may already have been popped, or have been overtaken by a
+    /// greater element pushed into a subqueue that was already visited. In
+    /// exchange it never holds more than one lock, unlike
+    /// [`peek_global_min()`], which has to lock everything at once. When no
+    /// other thread is using `self`, it's the true maximum.
+    #[must_use]
+    pub fn peek_max_approx(&self) -> Option<T> {
+        let mut max: Option<T> = None;
+
+        for queue in self.queues.as_ref() {
+            let top = queue.with_locked(|pq| {
+                pq.peek().filter(|&top| max.as_ref().map_or(true, |max| top > max)).cloned()
+            });
+
+            if top.is_some() {
+                max = top;
+            }
+        }
+
+        max
+    }
+
+    /// Clone every element into a descending sorted [`Vec`], leaving `self`
+    /// as it is.
+    ///
+    /// Each subqueue is locked only while it's being copied, so this is a
+    /// snapshot of every subqueue but not of the structure as a whole:
+    /// elements pushed or popped concurrently may show up in some subqueues'
+    /// snapshots and not others. For a consistent view, don't run this while
+    /// other threads are using `self`.
+    #[must_use]
+    pub fn peek_sorted(&self) -> Vec<T> {
+        let mut vec = Vec::with_capacity(self.len());
+
+        for queue in self.queues.as_ref() {
+            queue.with_locked(|pq| vec.extend_from_slice(pq.as_slice()));
+        }
+
+        vec.sort_unstable_by(|l, r| l.cmp(r).reverse());
+        vec
+    }
+
+    /// Clone every element of `slice` into the structure.
+    ///
+    /// Each subqueue takes every `n`th element of the slice, starting from a
+    /// random subqueue, and clones them into its heap under a single lock, so
+    /// [`BinaryHeap`] can heapify them in bulk. Striding rather than cutting
+    /// the slice into contiguous runs keeps a sorted slice from landing as
+    /// one range of priorities per subqueue. Bounded queues push one at a
+    /// time so that they can wait for room, like [`extend_ref()`].
+    ///
+    /// [`extend_ref()`]: MilkPQ::extend_ref
+    pub fn extend_from_slice(&self, slice: &[T]) {
+        if self.bound.is_some() {
+            for t in slice {
+                self.push(t.clone());
+            }
+
+            return;
+        }
+
+        let limit = self.queues.len();
+        let start = self.with_rng(|rng| self.sample_index(rng));
+
+        if self.track_len {
+            self.len.fetch_add(slice.len(), order::COUNT);
+        }
+
+        for offset in 0..limit.min(slice.len()) {
+            let queue = &self.queues[(start + offset) % limit];
+            queue.extend(slice[offset..].iter().step_by(limit).cloned());
+        }
+    }
+}
+
+impl<T: Ord + Copy, S, A: Allocator + Clone> MilkPQ<T, S, A> {
+    /// Like [`extend_ref()`], but copies the elements out of an iterator of
+    /// references.
+    ///
+    /// Exactly like [`Extend<&T>`](Extend), except it doesn't mutably borrow
+    /// `self`.
+    pub fn extend_copied<'a, I>(&self, iter: I)
+    where
+        I: IntoIterator<Item = &'a T>,
+        T: 'a,
+    {
+        self.extend_ref(iter.into_iter().copied());
+    }
+}
+
+/// A locked subqueue's maximum element, returned by [`MilkPQ::pop_guard()`].
+///
+/// Derefs to the element. [`commit()`](PopGuard::commit) pops it, and
+/// dropping the guard unlocks the subqueue with the element left in place.
+///
+/// Like a `MutexGuard`, it can only be shared between threads if `T` is
+/// [`Sync`], and it can never be sent to another thread, since the subqueue
+/// has to be unlocked by the thread that locked it:
+///
+/// ```
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<milkpq::PopGuard<'static, i32>>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<milkpq::PopGuard<'static, std::cell::Cell<i32>>>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+/// assert_send::<milkpq::PopGuard<'static, i32>>();
+/// ```
+#[must_use = "dropping the guard leaves the element in place"]
+pub struct PopGuard<'a, T: Ord, S = RandomState, A: Allocator = Global> {
+    mpq: &'a MilkPQ<T, S, A>,
+    guard: LockGuard<'a, T, A>,
+}
+
+impl<T: Ord, S, A: Allocator + Clone> PopGuard<'_, T, S, A> {
+    /// Pop the element and unlock the subqueue.
+    // It can't panic, since nothing else can empty the subqueue while the
+    // guard holds its lock.
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn commit(mut self) -> T {
+        let t = self.guard.heap().pop();
+        drop(self.guard);
+        self.mpq.popped(t).expect("a PopGuard's subqueue is never empty")
+    }
+}
+
+impl<T: Ord, S, A: Allocator> Deref for PopGuard<'_, T, S, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.heap_ref().peek().expect("a PopGuard's subqueue is never empty")
+    }
+}
+
+impl<T: Ord + Debug, S, A: Allocator> Debug for PopGuard<'_, T, S, A> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("PopGuard").field(&**self).finish()
+    }
+}
+
+/// A subqueue's heap, borrowed from [`MilkPQ::heaps_mut()`].
+///
+/// When dropped, this updates the counters behind [`MilkPQ::len()`] and
+/// [`MilkPQ::total_capacity()`] with whatever changed.
+#[derive(Debug)]
+pub struct HeapMut<'a, T: Ord, A: Allocator = Global> {
+    heap: &'a mut Heap<T, A>,
+    len: &'a mut usize,
+    capacity: &'a mut usize,
+    total_len: &'a AtomicUsize,
+    total_capacity: &'a AtomicUsize,
+}
+
+#[cfg(feature = "allocator_api")]
+impl<T: Ord, A: Allocator> Deref for HeapMut<'_, T, A> {
+    type Target = BinaryHeap<T, A>;
+
+    fn deref(&self) -> &BinaryHeap<T, A> {
+        self.heap
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<T: Ord, A: Allocator> DerefMut for HeapMut<'_, T, A> {
+    fn deref_mut(&mut self) -> &mut BinaryHeap<T, A> {
+        self.heap
+    }
+}
+
+/// Only for the global allocator, the only one there is without the
+/// `allocator_api` feature, so that the target is a plain [`BinaryHeap`].
+#[cfg(not(feature = "allocator_api"))]
+impl<T: Ord> Deref for HeapMut<'_, T> {
+    type Target = BinaryHeap<T>;
+
+    fn deref(&self) -> &BinaryHeap<T> {
+        self.heap
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::atomic::Ordering;
-    use super::*;
+#[cfg(not(feature = "allocator_api"))]
+impl<T: Ord> DerefMut for HeapMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut BinaryHeap<T> {
+        self.heap
+    }
+}
+
+impl<T: Ord, A: Allocator> Drop for HeapMut<'_, T, A> {
+    fn drop(&mut self) {
+        fn update(old: &mut usize, new: usize, total: &AtomicUsize) {
+            if new > *old {
+                total.fetch_add(new - *old, order::COUNT);
+            } else {
+                total.fetch_sub(*old - new, order::COUNT);
+            }
+
+            *old = new;
+        }
+
+        update(self.len, self.heap.len(), self.total_len);
+        update(self.capacity, self.heap.capacity(), self.total_capacity);
+    }
+}
+
+/// A subqueue's lock, apart from the heap it guards.
+///
+/// By default this is a CAS spinlock on an `AtomicBool`. The
+/// `mutex-backend` feature swaps it for a `parking_lot` mutex, which parks
+/// a thread waiting in [`Queue::lock()`] instead of spinning. Either way the
+/// heap stays in an [`UnsafeCell`] next to it, which is all a
+/// `parking_lot::Mutex<BinaryHeap<T>>` would be anyway.
+#[cfg(not(feature = "mutex-backend"))]
+struct RawLock(AtomicBool);
+
+#[cfg(not(feature = "mutex-backend"))]
+impl RawLock {
+    fn new() -> Self {
+        RawLock(AtomicBool::new(false))
+    }
+
+    #[cfg(not(feature = "strong-cas"))]
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.0.compare_exchange_weak(false, true, order::LOCK, order::LOCK_FAILURE).is_ok()
+    }
+
+    /// Like the weak version, but never fails spuriously, which can save
+    /// retries on LL/SC architectures like ARM.
+    #[cfg(feature = "strong-cas")]
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.try_lock_strong()
+    }
+
+    #[inline]
+    fn try_lock_strong(&self) -> bool {
+        self.0.compare_exchange(false, true, order::LOCK, order::LOCK_FAILURE).is_ok()
+    }
+
+    /// Must only be called while the lock is held.
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.0.store(false, order::UNLOCK);
+    }
+
+    /// Only a hint for skipping the subqueue, so it's loaded like the
+    /// length counters it's checked alongside.
+    fn is_locked(&self) -> bool {
+        self.0.load(order::COUNT)
+    }
+}
+
+#[cfg(feature = "mutex-backend")]
+struct RawLock(parking_lot::RawMutex);
+
+#[cfg(feature = "mutex-backend")]
+impl RawLock {
+    fn new() -> Self {
+        RawLock(parking_lot::RawMutex::INIT)
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.0.try_lock()
+    }
+
+    /// `parking_lot`'s `try_lock` never fails spuriously already.
+    #[inline]
+    fn try_lock_strong(&self) -> bool {
+        self.try_lock()
+    }
+
+    fn lock(&self) {
+        self.0.lock();
+    }
+
+    /// Must only be called while the lock is held.
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.0.unlock();
+    }
+
+    /// `parking_lot` always loads this `Relaxed`, so `debug-seqcst` can't
+    /// strengthen it.
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+
+/// Locking and unlocking by hand, to test how locked subqueues are handled.
+#[cfg(test)]
+impl RawLock {
+    fn hold(&self) {
+        assert!(self.try_lock_strong());
+    }
+
+    fn release(&self) {
+        unsafe { self.unlock() };
+    }
+}
+
+struct Queue<T: Ord, A: Allocator = Global> {
+    pq: UnsafeCell<Heap<T, A>>,
+    raw_lock: RawLock,
+    /// The length of `pq`, updated whenever it changes under the lock, for
+    /// reading without locking.
+    len: AtomicUsize,
+    /// The capacity of `pq` like `len`, and the total it's counted in.
+    capacity: AtomicUsize,
+    total_capacity: Arc<AtomicUsize>,
+    /// How many times the lock was tried, and how many of those failed.
+    attempts: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+unsafe impl<T: Ord + Send, A: Allocator + Send> Send for Queue<T, A> {}
+unsafe impl<T: Ord + Send, A: Allocator + Send> Sync for Queue<T, A> {}
+
+impl<T: Ord, A: Allocator> IntoIterator for Queue<T, A> {
+    type Item = T;
+    type IntoIter = HeapIntoIter<T, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.pq.into_inner().into_iter()
+    }
+}
+
+impl<T: Ord + Clone, A: Allocator + Clone> Clone for Queue<T, A> {
+    fn clone(&self) -> Self {
+        Queue::new(self.with_locked(|pq| pq.clone()))
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        let pq = self.pq.get_mut();
+        source.with_locked(|source| pq.clone_from(source));
+        *self.len.get_mut() = pq.len();
+        *self.capacity.get_mut() = pq.capacity();
+    }
+}
+
+impl<T: Ord + Debug, A: Allocator> Debug for Queue<T, A> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        // A strong CAS, so that an unlocked subqueue is never reported as
+        // locked because of a spurious failure.
+        if self.raw_lock.try_lock_strong() {
+            LockGuard::new(self).heap().fmt(f)
+        } else {
+            f.write_str("<locked>")
+        }
+    }
+}
+
+/// Proof that a subqueue's lock is held, giving access to its heap.
+///
+/// This is the only place the heap is reached through the [`UnsafeCell`],
+/// which is sound because the lock is held for as long as the guard lives.
+/// The lock is released when the guard is dropped, including while unwinding
+/// from a panic in the middle of an operation, so a panicking [`Ord`] impl
+/// can't leave a subqueue locked forever.
+///
+/// It also remembers the heap's capacity when the lock was taken, so that a
+/// reallocation can be added to the total capacity on unlock.
+struct LockGuard<'a, T: Ord, A: Allocator> {
+    queue: &'a Queue<T, A>,
+    capacity: usize,
+    _heap: PhantomData<&'a mut T>,
+    /// Keeps the guard on the thread that took the lock, which a
+    /// `parking_lot` mutex needs to be unlocked from.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Hands out `&T` like a `MutexGuard`, so it's only [`Sync`] if `T` is.
+unsafe impl<T: Ord + Sync, A: Allocator + Sync> Sync for LockGuard<'_, T, A> {}
+
+impl<'a, T: Ord, A: Allocator> LockGuard<'a, T, A> {
+    /// Must only be called once the lock has been taken.
+    #[inline]
+    fn new(queue: &'a Queue<T, A>) -> Self {
+        // The stored capacity is only ever written under the lock.
+        let capacity = queue.capacity.load(order::COUNT);
+        LockGuard { queue, capacity, _heap: PhantomData, _not_send: PhantomData }
+    }
+
+    #[inline]
+    fn heap(&mut self) -> &mut Heap<T, A> {
+        unsafe { &mut *self.queue.pq.get() }
+    }
+
+    #[inline]
+    fn heap_ref(&self) -> &Heap<T, A> {
+        unsafe { &*self.queue.pq.get() }
+    }
+}
+
+impl<T: Ord, A: Allocator> Drop for LockGuard<'_, T, A> {
+    fn drop(&mut self) {
+        let (len, capacity) = {
+            let pq = self.heap();
+            (pq.len(), pq.capacity())
+        };
+
+        if capacity > self.capacity {
+            self.queue.total_capacity.fetch_add(capacity - self.capacity, order::COUNT);
+        } else if capacity < self.capacity {
+            self.queue.total_capacity.fetch_sub(self.capacity - capacity, order::COUNT);
+        }
+
+        self.queue.capacity.store(capacity, order::COUNT);
+        self.queue.len.store(len, order::COUNT);
+        // The guard only exists while the lock is held.
+        unsafe { self.queue.raw_lock.unlock() };
+    }
+}
+
+impl<T: Ord, A: Allocator + Clone> Queue<T, A> {
+    /// Makes `limit` empty subqueues with `cap` capacity each, allocated
+    /// with clones of `alloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there isn't room for `limit` subqueues, rather than
+    /// aborting on a failed allocation.
+    fn many(limit: usize, cap: usize, alloc: A) -> Vec<Self> {
+        let mut queues = Vec::new();
+        let reserved = queues.try_reserve_exact(limit);
+        assert!(reserved.is_ok(), "can't allocate {} subqueues", limit);
+        queues.extend(std::iter::repeat(alloc).take(limit).map(|alloc| Queue::new(allocator::heap_in::<T, A>(cap, alloc))));
+        queues
+    }
+
+    fn new(pq: Heap<T, A>) -> Self {
+        Queue {
+            len: AtomicUsize::new(pq.len()),
+            capacity: AtomicUsize::new(pq.capacity()),
+            total_capacity: Arc::new(AtomicUsize::new(pq.capacity())),
+            pq: UnsafeCell::new(pq),
+            raw_lock: RawLock::new(),
+            attempts: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    #[must_use = "must check if CAS failed"]
+    #[inline]
+    fn try_lock(&self) -> Option<LockGuard<'_, T, A>> {
+        let locked = self.raw_lock.try_lock();
+        self.attempts.fetch_add(1, order::COUNT);
+
+        if locked {
+            Some(LockGuard::new(self))
+        } else {
+            self.failures.fetch_add(1, order::COUNT);
+            None
+        }
+    }
+
+    #[cfg(not(feature = "mutex-backend"))]
+    fn lock(&self) -> LockGuard<'_, T, A> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            spin();
+        }
+    }
+
+    /// Parks on the mutex once trying it fails, instead of spinning.
+    #[cfg(feature = "mutex-backend")]
+    fn lock(&self) -> LockGuard<'_, T, A> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+
+        self.raw_lock.lock();
+        LockGuard::new(self)
+    }
+
+    /// Runs `f` on the heap, waiting for the lock first.
+    fn with_locked<R>(&self, f: impl FnOnce(&mut Heap<T, A>) -> R) -> R {
+        f(self.lock().heap())
+    }
+
+    /// Runs `f` on the heap if the lock can be taken right away.
+    fn try_with_locked<R>(&self, f: impl FnOnce(&mut Heap<T, A>) -> R) -> Option<R> {
+        self.try_lock().map(|mut guard| f(guard.heap()))
+    }
+
+    #[must_use = "must check if CAS failed"]
+    #[inline]
+    fn try_push(&self, t: T) -> Result<(), T> {
+        match self.try_lock() {
+            Some(mut guard) => {
+                guard.heap().push(t);
+                Ok(())
+            }
+            None => Err(t),
+        }
+    }
+
+    #[must_use = "must check if CAS failed"]
+    #[inline]
+    fn try_pop(&self) -> Result<Option<T>, ()> {
+        self.try_with_locked(BinaryHeap::pop).ok_or(())
+    }
+
+    fn push(&self, t: T) {
+        self.with_locked(|pq| pq.push(t));
+    }
+
+    fn extend<I: IntoIterator<Item = T>>(&self, iter: I) {
+        self.with_locked(|pq| pq.extend(iter));
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.with_locked(BinaryHeap::pop)
+    }
+
+    fn clear(&self) -> usize {
+        self.with_locked(|pq| {
+            let len = pq.len();
+            pq.clear();
+            len
+        })
+    }
+
+    fn shrink_to(&self, min_capacity: usize) {
+        self.with_locked(|pq| pq.shrink_to(min_capacity));
+    }
+
+    /// Whether there was room for `additional` more elements as of the last
+    /// unlock, so that reserving it can skip the lock.
+    fn has_room(&self, additional: usize) -> bool {
+        self.capacity.load(order::COUNT).saturating_sub(self.len_relaxed()) >= additional
+    }
+
+    fn reserve(&self, additional: usize) {
+        if !self.has_room(additional) {
+            self.with_locked(|pq| pq.reserve(additional));
+        }
+    }
+
+    fn reserve_exact(&self, additional: usize) {
+        if !self.has_room(additional) {
+            self.with_locked(|pq| pq.reserve_exact(additional));
+        }
+    }
+
+    fn take(&mut self) -> Heap<T, A> {
+        let pq = self.pq.get_mut();
+        let new = allocator::heap_like::<T, A>(pq, pq.capacity());
+        *self.len.get_mut() = 0;
+        std::mem::replace(pq, new)
+    }
+
+    fn retain<F: FnMut(&T) -> bool>(&mut self, f: F) {
+        let pq = self.pq.get_mut();
+        pq.retain(f);
+        *self.len.get_mut() = pq.len();
+    }
+
+    fn drain_filter<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> Vec<T> {
+        let pq = self.pq.get_mut();
+        let mut kept = allocator::take::<T, A>(pq).into_vec();
+        let mut removed = Vec::new();
+        let mut i = 0;
+
+        // The order doesn't matter since `kept` is heapified again after, so
+        // each removal is a swap instead of a shift.
+        while i < kept.len() {
+            if f(&kept[i]) {
+                removed.push(kept.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+
+        *pq = BinaryHeap::from(kept);
+        self.recount();
+        removed
+    }
+
+    /// Brings the length and capacity counters up to date after `pq` was
+    /// changed through `&mut self`, the same way dropping a [`LockGuard`]
+    /// does.
+    fn recount(&mut self) {
+        let pq = self.pq.get_mut();
+        let (len, capacity) = (pq.len(), pq.capacity());
+        let old = std::mem::replace(self.capacity.get_mut(), capacity);
+
+        if capacity > old {
+            self.total_capacity.fetch_add(capacity - old, order::COUNT);
+        } else if capacity < old {
+            self.total_capacity.fetch_sub(old - capacity, order::COUNT);
+        }
+
+        *self.len.get_mut() = len;
+    }
+
+    /// Removes one element equal to `x`, if the heap has any, under a
+    /// single lock.
+    fn remove(&self, x: &T) -> Option<T> {
+        self.with_locked(|pq| {
+            let i = pq.as_slice().iter().position(|t| t == x)?;
+            let mut vec = allocator::take::<T, A>(pq).into_vec();
+            let t = vec.swap_remove(i);
+            *pq = BinaryHeap::from(vec);
+            Some(t)
+        })
+    }
+
+    fn lock_take(&self) -> Heap<T, A> {
+        self.with_locked(|pq| {
+            let new = allocator::heap_like::<T, A>(pq, pq.capacity());
+            std::mem::replace(pq, new)
+        })
+    }
+
+    /// The length of the subqueue as of its last change, without locking.
+    fn len_relaxed(&self) -> usize {
+        self.len.load(order::COUNT)
+    }
+
+    /// Whether the subqueue was empty as of its last change and isn't
+    /// locked, so it can be passed over without locking it. A locked
+    /// subqueue might be in the middle of a push, so it doesn't count.
+    fn looks_empty(&self) -> bool {
+        self.len_relaxed() == 0 && !self.raw_lock.is_locked()
+    }
+}
+
+impl<T: Ord + Clone, A: Allocator + Clone> Queue<T, A> {
+    fn clone_with_capacity(&self) -> Self {
+        let vec = self.with_locked(|pq| {
+            let mut vec = allocator::buf_like::<T, A>(pq, pq.capacity());
+            vec.extend_from_slice(pq.as_slice());
+            vec
+        });
+
+        Queue::new(BinaryHeap::from(vec))
+    }
+
+    fn peek(&self) -> Option<T> {
+        self.with_locked(|pq| pq.peek().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering::{self, Relaxed};
+    use super::*;
+
+    #[test]
+    fn try_push() {
+        let q = Queue::<i32>::new(BinaryHeap::new());
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+        assert_eq!(q.try_push(1), Ok(()));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
+        q.raw_lock.hold();
+        assert_eq!(q.try_push(2), Err(2));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
+        q.raw_lock.release();
+        assert_eq!(q.try_push(2), Ok(()));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn try_pop() {
+        let mut bheap = BinaryHeap::new();
+        bheap.push(1);
+        bheap.push(2);
+        let q = Queue::<i32>::new(bheap);
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 2);
+        assert_eq!(q.try_pop(), Ok(Some(2)));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
+        q.raw_lock.hold();
+        assert_eq!(q.try_pop(), Err(()));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
+        q.raw_lock.release();
+        assert_eq!(q.try_pop(), Ok(Some(1)));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+        assert_eq!(q.try_pop(), Ok(None));
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+    }
+
+    #[test]
+    fn take() {
+        let mut bheap = BinaryHeap::new();
+        bheap.push(1);
+        bheap.push(2);
+        bheap.push(0);
+        let mut q = Queue::<i32>::new(bheap.clone());
+        assert_eq!(bheap.into_sorted_vec(), q.take().into_sorted_vec());
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+    }
+
+    #[test]
+    fn queue_drain_filter() {
+        let mut q = Queue::<i32>::new((0..100).collect());
+        let mut removed = q.drain_filter(|t| t % 2 == 0);
+        removed.sort_unstable();
+        assert_eq!(removed, (0..100).step_by(2).collect::<Vec<_>>());
+        assert_eq!(q.len_relaxed(), 50);
+        let capacity = q.pq.get_mut().capacity();
+        assert_eq!(*q.capacity.get_mut(), capacity);
+        assert_eq!(q.total_capacity.load(Relaxed), capacity);
+    }
+
+    #[test]
+    fn queue_clear() {
+        let mut bheap = BinaryHeap::new();
+        bheap.push(1);
+        bheap.push(2);
+        let q = Queue::<i32>::new(bheap);
+        q.clear();
+        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+    }
+
+    #[test]
+    fn shrink_to() {
+        let q = MilkPQ::with_capacity_and_queues(100, 4);
+        q.push_to(0, 1);
+        q.shrink_to(40);
+
+        for queue in &*q.queues {
+            let cap = unsafe { queue.pq.get().as_ref() }.unwrap().capacity();
+            assert!((10..100).contains(&cap));
+        }
+    }
+
+    #[test]
+    fn into_sorted_vec() {
+        let q = MilkPQ::new();
+        let mut vs = (0..100).collect::<Vec<_>>();
+        vs.shuffle(&mut *PRNG.borrow_mut());
+        q.extend_ref(vs);
+        vs = q.into_sorted_vec();
+        assert_eq!(vs, (0..100).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn into_sorted_vec_duplicates() {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct Tagged(u8, u32);
+
+        impl PartialOrd for Tagged {
+            fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Tagged {
+            fn cmp(&self, other: &Self) -> CmpOrdering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let q = MilkPQ::with_queues(4);
+        q.extend_ref((0..300).map(|tag| Tagged((tag % 5) as u8, tag)));
+        let sorted = q.into_sorted_vec();
+
+        // Only the keys are in order. Equal keys may come in any order, so
+        // the tags are checked as a multiset.
+        assert!(sorted.windows(2).all(|w| w[0].0 >= w[1].0));
+        let mut tags = sorted.iter().map(|t| (t.0, t.1)).collect::<Vec<_>>();
+        tags.sort_unstable();
+        let mut expected = (0..300).map(|tag| ((tag % 5) as u8, tag)).collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(tags, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity overflow")]
+    fn with_capacity_and_queues_overflow() {
+        let _ = MilkPQ::<u64>::with_capacity_and_queues(usize::MAX / 2, 4);
+    }
+
+    #[test]
+    fn pop_at() {
+        let q = MilkPQ::with_queues(4);
+        q.push_to(2, 1);
+        q.push_to(2, 3);
+        assert_eq!(q.pop_at(0), None);
+        assert_eq!(q.peek_at(2), Some(3));
+        assert_eq!(q.pop_at(2), Some(3));
+        assert_eq!(q.pop_at(2), Some(1));
+        assert_eq!(q.pop_at(2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn pop_at_out_of_range() {
+        MilkPQ::<i32>::with_queues(4).pop_at(4);
+    }
+
+    #[test]
+    fn try_pop_at() {
+        let q = MilkPQ::with_queues(4);
+        q.push_to(1, 5);
+        assert_eq!(q.try_pop_at(0), Ok(None));
+        q.queues[1].raw_lock.hold();
+        assert_eq!(q.try_pop_at(1), Err(PopError::Locked));
+        assert_eq!(q.len(), 1);
+        q.queues[1].raw_lock.release();
+        assert_eq!(q.try_pop_at(1), Ok(Some(5)));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn try_pop_at_out_of_range() {
+        let _ = MilkPQ::<i32>::with_queues(4).try_pop_at(4);
+    }
+
+    #[test]
+    fn push_to() {
+        let q = MilkPQ::with_queues(4);
+        q.push_to(1, 5);
+        q.push_to(1, 7);
+        q.push_to(3, 6);
+        assert_eq!(q.pop_at(1), Some(7));
+        assert_eq!(q.pop_at(1), Some(5));
+        assert_eq!(q.pop_at(1), None);
+        assert_eq!(q.pop_at(3), Some(6));
+    }
+
+    #[test]
+    fn push_keyed() {
+        let q = MilkPQ::with_queues(8);
+
+        for i in 0..20 {
+            q.push_keyed("a", i);
+        }
+
+        let lens = q.queues.iter()
+            .map(|q| unsafe { q.pq.get().as_ref() }.unwrap().len())
+            .collect::<Vec<_>>();
+        assert!(lens.contains(&20));
+        assert_eq!(lens.iter().sum::<usize>(), 20);
+    }
+
+    #[test]
+    fn pop_or_none() {
+        let q = MilkPQ::with_queues(4);
+        q.push_to(3, 1);
+        assert_eq!(q.pop_or_none(1000), Some(1));
+        assert_eq!(q.pop_or_none(1000), None);
+
+        q.push_to(3, 1);
+        for queue in &*q.queues {
+            queue.raw_lock.hold();
+        }
+        assert_eq!(q.pop_or_none(1000), None);
+    }
+
+    #[test]
+    fn is_full() {
+        let q = MilkPQ::with_bound_and_queues(3, 2);
+        assert!(!q.is_full());
+        q.push(1);
+        q.push(2);
+        assert!(!q.is_full());
+        assert_eq!(q.try_push(3), Ok(()));
+        assert!(q.is_full());
+        assert_eq!(q.try_push(4), Err(PushError::Full(4)));
+        assert_eq!(q.len(), 3);
+        assert!(q.strong_pop().is_some());
+        assert!(!q.is_full());
+        assert!(!MilkPQ::<i32>::new().is_full());
+    }
+
+    #[test]
+    fn drain_into() {
+        let mut q = (0..10).collect::<MilkPQ<_>>();
+        let mut vec = vec![-1];
+        q.drain_into(&mut vec);
+        assert_eq!(vec.len(), 11);
+        assert!(q.is_empty());
+        assert_eq!(q.strong_pop(), None);
+        vec.sort_unstable();
+        assert_eq!(vec, (-1..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn len_histogram() {
+        let q = MilkPQ::with_queues(4);
+        assert_eq!(q.len_histogram(3), [4, 0, 0]);
+        q.extend_ref(0..400);
+        assert_eq!(q.len_histogram(5), [4, 0, 0, 0, 0]);
+
+        let q = MilkPQ::with_queues(4);
+
+        for (i, &len) in [0, 10, 20, 30].iter().enumerate() {
+            for t in 0..len {
+                q.push_to(i, t);
+            }
+        }
+
+        assert_eq!(q.len_histogram(4), [1, 1, 1, 1]);
+        assert_eq!(q.len_histogram(2), [2, 2]);
+        assert_eq!(q.len_histogram(1), [4]);
+    }
+
+    #[test]
+    fn split_drain() {
+        let mut q = MilkPQ::with_queues(4);
+        q.extend_ref((0..10).chain(0..10));
+        let chunks = q.split_drain(3);
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [7, 7, 6]);
+        assert!(q.is_empty());
+        assert_eq!(q.strong_pop(), None);
+
+        let mut all = chunks.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..10).flat_map(|i| [i, i]).collect::<Vec<_>>());
+
+        q.push(1);
+        assert_eq!(q.split_drain(3), [vec![1], vec![], vec![]]);
+    }
+
+    #[test]
+    fn with_queues_for_threads() {
+        assert_eq!(MilkPQ::<i32>::with_queues_for_threads(3).num_queues(), 12);
+        assert_eq!(MilkPQ::<i32>::with_oversubscription(3, 2).num_queues(), 6);
+    }
+
+    #[test]
+    fn merge() {
+        let a = MilkPQ::with_queues(4);
+        a.extend_ref(0..50);
+        let b = MilkPQ::with_queues(4);
+        b.extend_ref(50..100);
+        let q = a.merge(b);
+        assert_eq!(q.num_queues(), 4);
+        assert_eq!(q.len(), 100);
+        assert_eq!(q.into_sorted_vec(), (0..100).rev().collect::<Vec<_>>());
+
+        let a = MilkPQ::with_queues(3);
+        a.extend_ref(0..50);
+        let b = MilkPQ::with_queues(7);
+        b.extend_ref(50..100);
+        let q = a.merge(b);
+        assert_eq!(q.num_queues(), 3);
+        assert_eq!(q.len(), 100);
+        assert_eq!(q.into_sorted_vec(), (0..100).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn into_sorted_vec_by() {
+        let q = (0..10).collect::<MilkPQ<_>>();
+        let vs = q.into_sorted_vec_by(|l, r| (l % 3, l).cmp(&(r % 3, r)));
+        assert_eq!(vs, vec![0, 3, 6, 9, 1, 4, 7, 2, 5, 8]);
+    }
+
+    #[test]
+    fn len_approx() {
+        let q = MilkPQ::with_queues(16);
+
+        for i in 0..16 {
+            for t in 0..10 {
+                q.push_to(i, t);
+            }
+        }
+
+        assert_eq!(q.len_approx(), 160);
+        q.push(0);
+        assert!((150..=170).contains(&q.len_approx()));
+    }
+
+    #[test]
+    fn retain() {
+        let mut q = (0..100).collect::<MilkPQ<_>>();
+        q.retain(|&t| t % 2 == 0);
+        assert_eq!(q.len(), 50);
+        assert_eq!(q.into_sorted_vec(), (0..100).step_by(2).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn update_each() {
+        let mut q = MilkPQ::with_queues(4);
+        q.extend_ref(0..100);
+        let capacity = q.total_capacity();
+        q.update_each(|t| *t *= 2);
+        assert_eq!(q.len(), 100);
+        assert_eq!(q.total_capacity(), capacity);
+        assert_eq!(q.clone().into_sorted_vec(), (0..100).rev().map(|t| t * 2).collect::<Vec<_>>());
+
+        // Negating reverses every heap's order, so it only pops right if the
+        // heaps were rebuilt.
+        q.update_each(|t| *t = -*t);
+        let mut popped = Vec::new();
+
+        while let Some(t) = q.strong_pop_global() {
+            popped.push(t);
+        }
+
+        assert_eq!(popped, (0..100).map(|t| -t * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retain_with_index() {
+        let mut q = MilkPQ::with_queues(4);
+        (0..4).for_each(|i| q.push_to(i, i * 10));
+        q.extend_ref(100..200);
+        let mut first = q.queues[0].pq.get_mut().clone().into_sorted_vec();
+        first.reverse();
+        q.retain_with_index(|i, _| i == 0);
+        assert_eq!(q.len(), first.len());
+        assert_eq!(q.into_sorted_vec(), first);
+    }
+
+    #[test]
+    fn drain_filter() {
+        let mut q = (0..100).collect::<MilkPQ<_>>();
+        let mut odd = q.drain_filter(|&t| t % 2 == 1);
+        odd.sort_unstable();
+        assert_eq!(odd, (1..100).step_by(2).collect::<Vec<_>>());
+        assert_eq!(q.len(), 50);
+        assert_eq!(q.into_sorted_vec(), (0..100).step_by(2).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pop_skips_locked() {
+        let q = MilkPQ::with_queues(2);
+        q.push_to(1, 1);
+        q.queues[0].raw_lock.hold();
+
+        for _ in 0..100 {
+            assert_eq!(q.pop(), Some(1));
+            q.push_to(1, 1);
+        }
+    }
+
+    #[test]
+    fn extend_ref() {
+        let q = MilkPQ::with_queues(8);
+        q.extend_ref(0..100_000);
+        assert_eq!(q.len(), 100_000);
+        assert!(q.queues.iter().all(|q| q.len_relaxed() > 0));
+        assert_eq!(q.into_sorted_vec(), (0..100_000).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn extend_copied() {
+        let slice = (0..100).collect::<Vec<i32>>();
+        let mut q = MilkPQ::with_queues(4);
+        q.extend(&slice);
+        q.extend(slice.iter());
+        q.extend_copied(&slice[..10]);
+        assert_eq!(q.len(), 210);
+        let mut expected = [&slice[..], &slice[..], &slice[..10]].concat();
+        expected.sort_unstable_by(|l, r| r.cmp(l));
+        assert_eq!(q.into_sorted_vec(), expected);
+    }
+
+    #[test]
+    fn drain_concurrent() {
+        let q = MilkPQ::with_queues(8);
+        let mut drained = Vec::new();
+
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let q = &q;
+                s.spawn(move || q.extend_ref(i * 1000..(i + 1) * 1000));
+            }
+
+            for _ in 0..100 {
+                drained.append(&mut q.drain_concurrent());
+            }
+        });
+
+        drained.append(&mut q.drain_concurrent());
+        assert!(q.is_empty());
+        drained.sort_unstable();
+        assert_eq!(drained, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn peek_global_min() {
+        let mut vs = (10..1000).collect::<Vec<_>>();
+        vs.shuffle(&mut *PRNG.borrow_mut());
+        let q = vs.into_iter().collect::<MilkPQ<_>>();
+        assert_eq!(q.peek_global_min(), Some(10));
+        assert_eq!(MilkPQ::<i32>::new().peek_global_min(), None);
+    }
+
+    #[test]
+    fn debug() {
+        let q = MilkPQ::with_queues(2);
+        q.push_to(0, 1);
+        q.push_to(1, 2);
+        assert_eq!(format!("{q:?}"), "[[1], [2]]");
+        assert_eq!(format!("{:?}", q.debug_full()), "[[1], [2]]");
+        q.queues[1].raw_lock.hold();
+        assert_eq!(format!("{q:?}"), "[[1], <locked>]");
+    }
+
+    #[test]
+    fn clone_with_capacity() {
+        let q = MilkPQ::<i32>::with_capacity_and_queues(100, 4);
+        q.push_to(2, 1);
+        let c = q.clone_with_capacity();
+
+        for queue in &*c.queues {
+            assert!(unsafe { queue.pq.get().as_ref() }.unwrap().capacity() >= 100);
+        }
+
+        assert_eq!(c.len(), 1);
+        assert_eq!(c.pop_at(2), Some(1));
+    }
+
+    #[test]
+    fn resize_queues() {
+        let mut q = (0..100).collect::<MilkPQ<_>>();
+        q.resize_queues(3);
+        assert_eq!(q.num_queues(), 3);
+        assert_eq!(q.len(), 100);
+
+        for t in 100..200 {
+            q.push(t);
+        }
+
+        assert_eq!(q.into_sorted_vec(), (0..200).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn auto_tune() {
+        let mut q = MilkPQ::with_queues(2);
+        q.extend_ref(0..10);
+        q.reset_stats();
+
+        for queue in &*q.queues {
+            queue.raw_lock.hold();
+        }
+
+        assert_eq!(q.pop_or_none(100), None);
+        assert_eq!(q.stats(), Stats { lock_attempts: 100, lock_failures: 100 });
+
+        for queue in &*q.queues {
+            queue.raw_lock.release();
+        }
+
+        q.auto_tune();
+        assert_eq!(q.num_queues(), 4);
+        assert_eq!(q.len(), 10);
+        assert_eq!(q.stats(), Stats::default());
+
+        for _ in 0..100 {
+            q.push(0);
+            q.pop();
+        }
+
+        q.auto_tune();
+        assert_eq!(q.num_queues(), 2);
+    }
+
+    #[test]
+    fn strong_pop() {
+        let q = MilkPQ::new();
+        q.push(1);
+        q.push(2);
+        assert!(q.strong_pop().is_some());
+        assert!(q.strong_pop().is_some());
+        assert!(q.strong_pop().is_none());
+    }
+
+    #[test]
+    fn unlock_on_panic() {
+        #[derive(PartialEq, Eq)]
+        struct Bomb(bool);
+
+        impl PartialOrd for Bomb {
+            fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Bomb {
+            fn cmp(&self, other: &Self) -> CmpOrdering {
+                assert!(!self.0 && !other.0, "boom");
+                CmpOrdering::Equal
+            }
+        }
+
+        let q = Queue::<Bomb>::new(BinaryHeap::new());
+        q.push(Bomb(false));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| q.push(Bomb(true))));
+        assert!(result.is_err());
+        assert!(!q.raw_lock.is_locked());
+        assert_eq!(q.len_relaxed(), 2);
+    }
+
+    #[test]
+    fn pop_if() {
+        let q = MilkPQ::with_queues(1);
+        q.extend_ref(0..10);
+        assert_eq!(q.pop_if(|&t| t < 9), None);
+        assert_eq!(q.len(), 10);
+        assert_eq!(q.pop_if(|&t| t == 9), Some(9));
+        assert_eq!(q.len(), 9);
+    }
+
+    #[test]
+    fn pop_guard_commit() {
+        let q = MilkPQ::with_queues(1);
+        q.extend_ref(vec![String::from("a"), String::from("c"), String::from("b")]);
+        let guard = q.pop_guard().unwrap();
+        assert_eq!(*guard, "c");
+        assert_eq!(guard.len(), 1);
+        assert_eq!(guard.commit(), "c");
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.pop().as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn pop_guard_cancel() {
+        let q = MilkPQ::with_queues(1);
+        assert!(q.pop_guard().is_none());
+        q.extend_ref(0..10);
+
+        {
+            let guard = q.pop_guard().unwrap();
+            assert_eq!(*guard, 9);
+            assert_eq!(format!("{guard:?}"), "PopGuard(9)");
+            assert_eq!(q.try_pop_at(0), Err(PopError::Locked));
+        }
+
+        assert_eq!(q.len(), 10);
+        assert_eq!(q.pop(), Some(9));
+    }
+
+    #[test]
+    fn reserve_skips_spacious_queues() {
+        let q = MilkPQ::with_capacity_and_queues(100, 4);
+        q.extend_ref(0..40);
+        q.reset_stats();
+        q.reserve(300);
+        q.reserve_exact(360);
+        assert_eq!(q.stats().lock_attempts, 0);
+
+        q.reserve(1000);
+        assert_eq!(q.stats().lock_attempts, 4);
+        assert!(q.total_capacity() >= 1040);
+        q.reset_stats();
+        q.reserve_exact(1000);
+        assert_eq!(q.stats().lock_attempts, 0);
+    }
+
+    #[test]
+    fn total_capacity() {
+        let q = MilkPQ::with_queues(4);
+        assert_eq!(q.total_capacity(), 0);
+
+        for i in 0..1000 {
+            q.push(i);
+        }
+
+        let summed = |q: &MilkPQ<i32>| {
+            q.queues.iter().map(|q| unsafe { q.pq.get().as_ref() }.unwrap().capacity()).sum::<usize>()
+        };
+        assert_eq!(q.total_len(), 1000);
+        assert_eq!(q.total_capacity(), summed(&q));
+
+        q.clear();
+        q.shrink_to(0);
+        assert_eq!(q.total_capacity(), summed(&q));
+
+        let c = q.clone_with_capacity();
+        c.push_to(0, 0);
+        assert_eq!(c.total_capacity(), summed(&c));
+        assert_eq!(q.total_capacity(), summed(&q));
+    }
+
+    #[test]
+    fn into_sorted_vec_stable() {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        struct Task(u8, u32);
+
+        impl PartialOrd for Task {
+            fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Task {
+            fn cmp(&self, other: &Self) -> CmpOrdering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let q = MilkPQ::with_queues(4);
+        q.extend_ref((0..1000).map(|id| Task((id % 3) as u8, id)));
+        let drained = Vec::from(q.clone());
+        let sorted = q.into_sorted_vec_stable();
+
+        for prio in (0..3).rev() {
+            let ids = |v: &[Task]| v.iter().filter(|t| t.0 == prio).map(|t| t.1).collect::<Vec<_>>();
+            assert_eq!(ids(&sorted), ids(&drained));
+        }
+
+        assert!(sorted.windows(2).all(|w| w[0] >= w[1]));
+    }
+
+    #[test]
+    fn from_iter_capacity() {
+        let mut q = (0..10_000).collect::<MilkPQ<_>>();
+        assert_eq!(q.len(), 10_000);
+        // The exact size hint is split evenly and the elements are dealt out
+        // round-robin, so every subqueue gets at most its share and none of
+        // them grows past what was reserved up front.
+        let n = q.num_queues();
+        let share = 10_000_usize.div_ceil(n);
+        let cap = Vec::<i32>::with_capacity(share).capacity();
+
+        for queue in q.queues.as_mut() {
+            let pq = queue.pq.get_mut();
+            assert!(pq.len() == share || pq.len() == 10_000 / n);
+            assert_eq!(pq.capacity(), cap);
+        }
+
+        assert_eq!(q.total_capacity(), n * cap);
+    }
+
+    #[test]
+    fn from_iter_upper_hint() {
+        // Nothing is pushed, so all the capacity comes from the size hints.
+        let q = (0..10_000).filter(|_| false).collect::<MilkPQ<i32>>();
+        let per_queue = 10_000_usize.div_ceil(q.num_queues());
+        assert!(q.is_empty());
+        assert!(q.total_capacity() >= 10_000);
+        assert!(q.total_capacity() < per_queue * q.num_queues() * 2);
+
+        let q = (0..usize::MAX).take_while(|_| false).collect::<MilkPQ<usize>>();
+        assert!(q.total_capacity() < FROM_ITER_MAX_HINT * 2);
+
+        let q = (0..10_000).collect::<Vec<_>>().into_iter().collect::<MilkPQ<_>>();
+        assert_eq!(q.len(), 10_000);
+        assert!(q.total_capacity() >= 10_000);
+    }
+
+    #[test]
+    fn singleton() {
+        assert_eq!(MilkPQ::from_one_with_queues(5, 1).pop(), Some(5));
+        // With more subqueues, pop() can miss the one holding 5.
+        let q = MilkPQ::singleton(5);
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.strong_pop(), Some(5));
+        assert!(q.is_empty());
+
+        let q = MilkPQ::from_one_with_queues("root", 4);
+        assert_eq!(q.num_queues(), 4);
+        assert_eq!(q.into_sorted_vec(), ["root"]);
+    }
+
+    #[test]
+    fn extend_ref_balance() {
+        let q = MilkPQ::with_queues(7);
+        q.extend_ref(0..1000);
+        q.extend_ref(0..500);
+        // Each call is within one per subqueue, so two are within two.
+        let lens = q.queues.iter().map(Queue::len_relaxed).collect::<Vec<_>>();
+        let (min, max) = (lens.iter().min().unwrap(), lens.iter().max().unwrap());
+        assert!(max - min <= 2, "{:?}", lens);
+        assert_eq!(lens.iter().sum::<usize>(), 1500);
+    }
+
+    #[test]
+    fn extend_from_slice() {
+        let slice = [5, 3, 3, 9, -1, 0, 5, 5, 7, 2];
+        let q = MilkPQ::with_queues(4);
+        q.extend_from_slice(&slice);
+        q.extend_from_slice(&slice[..2]);
+        q.extend_from_slice(&[]);
+        assert_eq!(q.len(), 12);
+        let lens = q.queues.iter().map(Queue::len_relaxed).collect::<Vec<_>>();
+        assert_eq!(lens.iter().sum::<usize>(), 12);
+
+        let mut expected = slice.iter().chain(&slice[..2]).copied().collect::<Vec<_>>();
+        expected.sort_unstable_by(|l, r| r.cmp(l));
+        assert_eq!(q.into_sorted_vec(), expected);
+    }
+
+    #[test]
+    fn from_iter_bounded() {
+        let (q, overflow) = MilkPQ::from_iter_bounded(0..100, 30, 4);
+        assert_eq!(q.len(), 30);
+        assert!(q.is_full());
+        assert_eq!(overflow, (30..100).collect::<Vec<_>>());
+        assert_eq!(q.into_sorted_vec(), (0..30).rev().collect::<Vec<_>>());
+
+        let (q, overflow) = MilkPQ::from_iter_bounded(0..10, 30, 4);
+        assert_eq!((q.len(), overflow.len()), (10, 0));
+    }
+
+    #[test]
+    fn drain_sorted() {
+        use rand::seq::SliceRandom;
+
+        let mut elems = (0..1000).collect::<Vec<_>>();
+        elems.shuffle(&mut *PRNG.borrow_mut());
+        let mut q = elems.into_iter().collect::<MilkPQ<_>>();
+        assert_eq!(q.drain_sorted(), (0..1000).rev().collect::<Vec<_>>());
+        assert!(q.is_empty());
+        q.push(5);
+        assert_eq!(q.strong_pop(), Some(5));
+    }
+
+    #[test]
+    fn push_home() {
+        let q = Arc::new(MilkPQ::with_queues(8));
+
+        for i in 0..100 {
+            q.push(i);
+        }
+
+        // Uncontended pushes all take the fast path into the home subqueue.
+        let nonempty = q.queues.iter().filter(|queue| queue.len_relaxed() > 0).count();
+        assert_eq!(nonempty, 1);
+
+        let threads = (0..4)
+            .map(|_| {
+                let q = Arc::clone(&q);
+                std::thread::spawn(move || (0..1000).for_each(|i| q.push(i)))
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(q.len(), 4100);
+        let mut count = 0;
+
+        while q.strong_pop().is_some() {
+            count += 1;
+        }
+
+        assert_eq!(count, 4100);
+    }
+
+    #[test]
+    fn resize_queues_capacity() {
+        let mut q = (0..10_000).collect::<MilkPQ<_>>();
+        q.resize_queues(7);
+        let cap = Vec::<i32>::with_capacity(10_000usize.div_ceil(7)).capacity();
+
+        for queue in q.queues.as_mut() {
+            assert_eq!(queue.pq.get_mut().capacity(), cap);
+        }
+
+        assert_eq!(q.total_capacity(), 7 * cap);
+    }
+
+    #[test]
+    fn length_weighted_pop() {
+        let skewed = || {
+            let q = MilkPQ::with_queues(16);
+
+            for i in 0..1000 {
+                q.push_to(i % 2, i);
+            }
+
+            q
+        };
+        let nones = |q: &MilkPQ<usize>| (0..500).filter(|_| q.pop().is_none()).count();
+
+        assert!(nones(&skewed()) > 250);
+        assert_eq!(nones(&skewed().with_length_weighted_pop()), 0);
+    }
+
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<MilkPQ<i32>>();
+        assert_send_sync::<MilkPQ<String>>();
+        assert_send_sync::<MilkPQ<Arc<i32>>>();
+        assert_send_sync::<CancellableMilkPQ<i32>>();
+    }
+
+    #[test]
+    fn pop_all() {
+        let q = Arc::new(MilkPQ::with_queues(8));
+        let pushers = (0..4)
+            .map(|i| {
+                let q = Arc::clone(&q);
+                std::thread::spawn(move || (i * 1000..(i + 1) * 1000).for_each(|t| q.push(t)))
+            })
+            .collect::<Vec<_>>();
+        let mut popped = q.pop_all();
+
+        for pusher in pushers {
+            pusher.join().unwrap();
+        }
+
+        popped.append(&mut q.pop_all());
+        assert!(q.is_empty());
+        popped.sort_unstable();
+        assert_eq!(popped, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn max_queues() {
+        assert_eq!(MilkPQ::<i32>::with_queues(1).num_queues(), 1);
+        assert_eq!(MilkPQ::<i32>::with_queues(MAX_QUEUES + 1).num_queues(), MAX_QUEUES);
+        assert_eq!(MilkPQ::<i32>::with_queues_unchecked(MAX_QUEUES + 1).num_queues(), MAX_QUEUES + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one subqueue")]
+    fn zero_queues() {
+        let _ = MilkPQ::<i32>::with_queues(0);
+    }
+
+    #[test]
+    fn peek_sorted() {
+        let q = (0..1000).map(|i| i * 7 % 1000).collect::<MilkPQ<_>>();
+        assert_eq!(q.peek_sorted(), q.clone().into_sorted_vec());
+        assert_eq!(q.len(), 1000);
+    }
 
     #[test]
-    fn try_push() {
-        let q = Queue::new(BinaryHeap::new());
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
-        assert_eq!(q.try_push(1), Ok(()));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
-        q.cas_lock.store(true, Ordering::Release);
-        assert_eq!(q.try_push(2), Err(2));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
-        q.cas_lock.store(false, Ordering::Release);
-        assert_eq!(q.try_push(2), Ok(()));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 2);
+    fn clear_and_shrink() {
+        let q = MilkPQ::with_capacity_and_queues(100, 4);
+        q.extend_ref(0..400);
+        q.clear();
+        assert!(q.is_empty());
+        assert!(q.total_capacity() >= 400);
+        q.extend_ref(0..400);
+        q.clear_and_shrink();
+        assert!(q.is_empty());
+        assert_eq!(q.total_capacity(), 0);
+        q.push(1);
+        assert_eq!(q.strong_pop(), Some(1));
     }
 
     #[test]
-    fn try_pop() {
-        let mut bheap = BinaryHeap::new();
-        bheap.push(1);
-        bheap.push(2);
-        let q = Queue::new(bheap);
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 2);
-        assert_eq!(q.try_pop(), Ok(Some(2)));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
-        q.cas_lock.store(true, Ordering::Release);
-        assert_eq!(q.try_pop(), Err(()));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 1);
-        q.cas_lock.store(false, Ordering::Release);
-        assert_eq!(q.try_pop(), Ok(Some(1)));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
-        assert_eq!(q.try_pop(), Ok(None));
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+    fn into_sorted_into() {
+        let mut buf = vec![-1; 2000];
+        let cap = buf.capacity();
+
+        for n in [1000, 2000, 500] {
+            let q = (0..n).collect::<MilkPQ<_>>();
+            q.into_sorted_into(&mut buf);
+            assert_eq!(buf, (0..n).rev().collect::<Vec<_>>());
+            assert_eq!(buf.capacity(), cap);
+        }
     }
 
     #[test]
-    fn take() {
-        let mut bheap = BinaryHeap::new();
-        bheap.push(1);
-        bheap.push(2);
-        bheap.push(0);
-        let mut q = Queue::new(bheap.clone());
-        assert_eq!(bheap.into_sorted_vec(), q.take().into_sorted_vec());
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+    fn contention_callback() {
+        let q = MilkPQ::with_queues(4);
+        let seen = Arc::new(AtomicUsize::new(0));
+        let seen2 = Arc::clone(&seen);
+        q.set_contention_callback(1, move |failures| {
+            seen2.fetch_max(failures, Ordering::Relaxed);
+        });
+
+        q.push(1);
+        assert_eq!(seen.load(Ordering::Relaxed), 0);
+
+        // Hold every lock but one, so a pop has to keep retrying until it
+        // happens to pick the free subqueue.
+        for queue in &q.queues[1..] {
+            queue.raw_lock.hold();
+        }
+
+        while seen.load(Ordering::Relaxed) == 0 {
+            q.pop();
+        }
+
+        assert!(seen.load(Ordering::Relaxed) > 1);
+        q.clear_contention_callback();
+        seen.store(0, Ordering::Relaxed);
+
+        for _ in 0..100 {
+            q.pop();
+        }
+
+        assert_eq!(seen.load(Ordering::Relaxed), 0);
     }
 
     #[test]
-    fn queue_clear() {
-        let mut bheap = BinaryHeap::new();
-        bheap.push(1);
-        bheap.push(2);
-        let q = Queue::new(bheap);
+    fn into_iter() {
+        let q = MilkPQ::with_queues(4);
+        q.extend_ref(0..1000);
+        let mut all = q.clone().into_iter().collect::<Vec<_>>();
+        all.sort_unstable();
+        assert_eq!(all, (0..1000).collect::<Vec<_>>());
+
+        let mut iter = q.into_iter();
+        assert_eq!(iter.len(), 1000);
+        assert_eq!(iter.by_ref().take(5).count(), 5);
+        assert_eq!(iter.len(), 995);
+        // Only the first nonempty subqueue has been opened so far.
+        assert!(iter.queues.len() >= 2);
+
+        // The length comes from the subqueues, not the shared counter.
+        let mut q = (0..10).collect::<MilkPQ<_>>();
+        *q.len.get_mut() = 3;
+        let iter = q.into_iter();
+        assert_eq!(iter.len(), 10);
+        assert_eq!(iter.count(), 10);
+    }
+
+    #[test]
+    fn into_sorted_vec_deadline() {
+        use std::time::Duration;
+
+        let q = (0..1000).collect::<MilkPQ<_>>();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        assert_eq!(q.clone().into_sorted_vec_deadline(deadline), Ok(q.clone().into_sorted_vec()));
+
+        let mut unsorted = q.into_sorted_vec_deadline(Instant::now()).unwrap_err();
+        unsorted.sort_unstable();
+        assert_eq!(unsorted, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn push_pop() {
+        let q = MilkPQ::with_queues(1);
+        q.push(5);
+        assert_eq!(q.push_pop(7), Some(7));
+        assert_eq!(q.push_pop(3), Some(5));
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.pop(), Some(3));
+        assert_eq!(q.push_pop(1), Some(1));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn replace_max() {
+        let q = MilkPQ::with_queues(1);
+        assert_eq!(q.replace_max(5), None);
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.replace_max(7), Some(5));
+        assert_eq!(q.push_pop(9), Some(9));
+        assert_eq!(q.replace_max(9), Some(7));
+        assert_eq!(q.replace_max(3), Some(9));
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.pop(), Some(3));
+
+        // An empty pick gets `t` itself, not wherever `push()` would go.
+        for _ in 0..100 {
+            let mut q = MilkPQ::with_queues(2);
+            q.push_to(1, 0);
+
+            if q.replace_max(5).is_none() {
+                assert_eq!(q.heaps_mut().map(|heap| heap.len()).collect::<Vec<_>>(), [1, 1]);
+            }
+        }
+
+        // Pushing into the empty pick still reserves room in a bounded queue.
+        let q = MilkPQ::with_bound_and_queues(2, 2);
+
+        for t in 0..10 {
+            let _ = q.replace_max(t);
+        }
+
+        assert!(q.len() <= 2);
+        assert_eq!(q.len(), q.into_iter().count());
+
+        // A full queue replaces the maximum of whichever subqueue has one,
+        // even if the empty subqueue is picked first.
+        for _ in 0..100 {
+            let q = MilkPQ::with_bound_and_queues(1, 2);
+            q.push_to(0, 3);
+            assert_eq!(q.replace_max(5), Some(3));
+            assert_eq!(q.replace_max(4), Some(5));
+            assert_eq!(q.len(), 1);
+            assert_eq!(q.pop_at(0), Some(4));
+        }
+
+        let q = MilkPQ::with_bound_and_queues(0, 2);
+        assert_eq!(q.replace_max(5), Some(5));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn autosized_queues() {
+        let n = super::autosized_queues();
+        let threads = num_cpus::get().max(2);
+        assert!([1, 2, 4, 8].iter().any(|factor| n == (threads * factor).min(MAX_QUEUES)));
+        assert_eq!(super::autosized_queues(), n);
+        assert_eq!(MilkPQ::<i32>::new_autosized().num_queues(), n);
+    }
+
+    #[test]
+    fn heaps_mut() {
+        let mut q = MilkPQ::with_queues(4);
+        q.extend_ref(0..1000);
+
+        for mut heap in q.heaps_mut() {
+            heap.retain(|t| t % 2 == 0);
+            heap.shrink_to_fit();
+        }
+
+        let summed = q.queues.iter_mut().map(|q| q.pq.get_mut().capacity()).sum::<usize>();
+        assert_eq!(q.len(), 500);
+        assert_eq!(q.total_capacity(), summed);
+        assert_eq!(q.into_sorted_vec(), (0..1000).rev().filter(|t| t % 2 == 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_sized() {
+        let mut q = MilkPQ::<()>::with_capacity_and_queues(100, 4);
+        assert_eq!(q.total_capacity(), usize::MAX);
+
+        for _ in 0..100 {
+            q.push(());
+        }
+
+        assert_eq!(q.len(), 100);
+        assert_eq!(q.strong_pop(), Some(()));
+        assert_eq!(q.len(), 99);
+        assert_eq!(q.clone().into_sorted_vec().len(), 99);
+        assert_eq!(q.clone().into_iter().count(), 99);
+        assert_eq!(q.drain_sorted().len(), 99);
+        assert!(q.is_empty());
+
+        q.extend_ref(std::iter::repeat(()).take(50));
+        q.resize_queues(3);
+        q.shrink_to(0);
+        assert_eq!(q.len(), 50);
+        assert_eq!(q.drain().len(), 50);
+        assert_eq!(q.strong_pop(), None);
+        assert_eq!(q.total_capacity(), usize::MAX);
+    }
+
+    #[test]
+    fn clear_empty() {
+        let q = MilkPQ::<i32>::with_queues(4);
         q.clear();
-        assert_eq!(unsafe { q.pq.get().as_ref() }.unwrap().len(), 0);
+        assert_eq!(q.stats().lock_attempts, 0);
+        q.push(1);
+        q.reset_stats();
+        q.clear();
+        assert_eq!(q.stats().lock_attempts, 4);
+        assert!(q.is_empty());
     }
 
     #[test]
-    fn into_sorted_vec() {
-        let q = MilkPQ::new();
-        let mut vs = (0..100).collect::<Vec<_>>();
-        vs.shuffle(&mut *PRNG.borrow_mut());
-        q.extend_ref(vs);
-        vs = q.into_sorted_vec();
-        assert_eq!(vs, (0..100).rev().collect::<Vec<_>>());
+    fn strong_pop_skips_locked() {
+        let q = MilkPQ::with_queues(4);
+        q.push_to(2, 7);
+        q.queues[0].raw_lock.hold();
+        q.queues[1].raw_lock.hold();
+        assert_eq!(q.strong_pop(), Some(7));
+        // The locked subqueues were skipped without trying their locks.
+        assert_eq!(q.queues[0].attempts.load(Relaxed), 0);
+        assert_eq!(q.queues[1].attempts.load(Relaxed), 0);
+        q.queues[0].raw_lock.release();
+        q.queues[1].raw_lock.release();
+        assert_eq!(q.strong_pop(), None);
     }
 
     #[test]
-    fn strong_pop() {
-        let q = MilkPQ::new();
+    fn strong_pop_waits_for_locked() {
+        let q = MilkPQ::with_queues(4);
+        q.push_to(1, 7);
+        q.queues[1].raw_lock.hold();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                q.queues[1].raw_lock.release();
+            });
+            assert_eq!(q.strong_pop(), Some(7));
+        });
+    }
+
+    #[test]
+    fn try_strong_pop() {
+        let q = MilkPQ::with_queues(4);
+        assert_eq!(q.try_strong_pop(), Ok(None));
+        q.push_to(2, 7);
+        q.queues[0].raw_lock.hold();
+        assert_eq!(q.try_strong_pop(), Ok(Some(7)));
+        assert_eq!(q.try_strong_pop(), Err(PopError::Locked));
+        q.queues[0].raw_lock.release();
+        assert_eq!(q.try_strong_pop(), Ok(None));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn pop_with_attempts() {
+        let q = MilkPQ::from_one_with_queues(3, 1);
+        assert_eq!(q.pop_with_attempts(), (Some(3), 1));
+        assert_eq!(q.pop_with_attempts(), (None, 1));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn strong_pop_global() {
+        let q = MilkPQ::with_queues(8);
+        assert_eq!(q.strong_pop_global(), None);
+
+        for t in 0..100 {
+            // Scattered so that no subqueue holds a contiguous range.
+            q.push_to(t * 5 % 8, t);
+        }
+
+        for t in (0..100).rev() {
+            assert_eq!(q.strong_pop_global(), Some(t));
+        }
+
+        assert_eq!(q.strong_pop_global(), None);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn strong_pop_sparse() {
+        let q = MilkPQ::with_queues(64);
+        q.push_to(40, 1);
+        q.reset_stats();
+        assert_eq!(q.strong_pop(), Some(1));
+        assert_eq!(q.stats().lock_attempts, 1);
+        assert_eq!(q.strong_pop(), None);
+        assert_eq!(q.stats().lock_attempts, 1);
+    }
+
+    #[test]
+    fn contains_and_remove() {
+        let q = MilkPQ::with_queues(8);
+        q.extend_ref(0..100);
+        q.extend_ref(0..100);
+        assert!(q.contains_and_remove(&5));
+        assert!(q.contains_and_remove(&5));
+        assert!(!q.contains_and_remove(&5));
+        assert!(!q.contains_and_remove(&1000));
+        assert_eq!(q.len(), 198);
+
+        // Every element is there once, and 4 threads race to remove each,
+        // so exactly one of them wins every time.
+        let q = MilkPQ::with_queues(8);
+        q.extend_ref(0..500);
+        let removed = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for t in 0..500 {
+                        if q.contains_and_remove(&t) {
+                            removed.fetch_add(1, Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(removed.into_inner(), 500);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn strong_pop_concurrent() {
+        let q = MilkPQ::with_queues(8);
+        q.extend_ref(0..10_000);
+        let mut popped = std::thread::scope(|s| {
+            let threads = (0..8)
+                .map(|_| s.spawn(|| std::iter::from_fn(|| q.strong_pop()).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            threads.into_iter().flat_map(|t| t.join().unwrap()).collect::<Vec<_>>()
+        });
+
+        popped.sort_unstable();
+        assert_eq!(popped, (0..10_000).collect::<Vec<_>>());
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn clone_from_reuses_queues() {
+        let source = MilkPQ::with_queues(4);
+        source.extend_ref(0..100);
+        let mut q = MilkPQ::with_capacity_and_queues(100, 4);
+        q.extend_ref(500..510);
+        let ptrs = q.queues.iter_mut().map(|q| q.pq.get_mut().as_slice().as_ptr()).collect::<Vec<_>>();
+        let capacity = q.total_capacity();
+
+        q.clone_from(&source);
+        assert_eq!(q.len(), 100);
+        assert_eq!(q.total_capacity(), capacity);
+        assert_eq!(q.queues.iter_mut().map(|q| q.pq.get_mut().as_slice().as_ptr()).collect::<Vec<_>>(), ptrs);
+        assert_eq!(q.clone().into_sorted_vec(), source.clone().into_sorted_vec());
+
+        let mut q = MilkPQ::with_queues(7);
+        q.clone_from(&source);
+        assert_eq!(q.num_queues(), 4);
+        assert_eq!(q.into_sorted_vec(), source.into_sorted_vec());
+    }
+
+    #[test]
+    fn lock_all_holds_nothing_while_waiting() {
+        use std::time::Duration;
+
+        let q = MilkPQ::with_queues(4);
+        q.extend_ref(0..100);
+        q.queues[3].raw_lock.hold();
+
+        std::thread::scope(|s| {
+            let attempts = q.queues[3].attempts.load(Relaxed);
+            let global = s.spawn(|| q.strong_pop_global());
+            let deadline = Instant::now() + Duration::from_secs(30);
+            let mut popped = 0;
+
+            while q.queues[3].attempts.load(Relaxed) == attempts && Instant::now() < deadline {
+                std::thread::yield_now();
+            }
+
+            // The global pop is stuck on subqueue 3 the whole time, but it
+            // mustn't keep the others locked while it waits.
+            while popped < 10 && Instant::now() < deadline {
+                if let Ok(Some(_)) = q.try_pop_at(popped % 3) {
+                    popped += 1;
+                }
+            }
+
+            // Released before checking, so that a failure doesn't hang on
+            // joining the global pop.
+            q.queues[3].raw_lock.release();
+            assert_eq!(popped, 10, "strong_pop_global held the other subqueues");
+            assert!(global.join().unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn strong_pop_global_progress() {
+        use std::sync::atomic::AtomicBool;
+        use std::time::Duration;
+
+        let q = MilkPQ::with_queues(8);
+        q.extend_ref(0..1000);
+        let (ops, done) = (AtomicUsize::new(0), AtomicBool::new(false));
+
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| while !done.load(Relaxed) {
+                    q.push(0);
+                    q.pop();
+                    ops.fetch_add(1, Relaxed);
+                });
+            }
+
+            let deadline = Instant::now() + Duration::from_secs(30);
+
+            for _ in 0..50 {
+                let before = ops.load(Relaxed);
+                assert!(q.strong_pop_global().is_some());
+
+                // The workers get their turn before the next global pop.
+                while ops.load(Relaxed) == before {
+                    assert!(Instant::now() < deadline, "the workers made no progress");
+                    std::thread::yield_now();
+                }
+            }
+
+            done.store(true, Relaxed);
+        });
+    }
+
+    #[test]
+    fn clone_during_multi_lock() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let q = Arc::new(MilkPQ::with_queues(8));
+        q.extend_ref(0..1000);
+        let done = Arc::new(AtomicBool::new(false));
+        let spawn = |f: fn(&MilkPQ<i32>)| {
+            let (q, done) = (Arc::clone(&q), Arc::clone(&done));
+            std::thread::spawn(move || while !done.load(Relaxed) {
+                f(&q);
+            })
+        };
+        let threads = [
+            spawn(|q| drop(q.clone())),
+            spawn(|q| drop(q.clone())),
+            spawn(|q| { let _ = q.peek_global_min(); }),
+            spawn(|q| { let _ = q.peek_global_min(); }),
+            spawn(|q| {
+                q.clear();
+                q.extend_ref(0..1000);
+            }),
+        ];
+
+        // Spawned threads are left behind on a hang instead of joined, so the
+        // test fails instead of hanging with them.
+        let (tx, rx) = mpsc::channel();
+        let checker = Arc::clone(&q);
+        std::thread::spawn(move || {
+            for _ in 0..200 {
+                drop(checker.clone());
+                let _ = checker.peek_global_min();
+            }
+            let _ = tx.send(());
+        });
+        let finished = rx.recv_timeout(Duration::from_secs(30));
+        done.store(true, Relaxed);
+        assert!(finished.is_ok(), "clone and peek_global_min deadlocked");
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn with_capacities() {
+        let mut q = MilkPQ::<i32>::with_capacities(&[10, 100, 10]);
+        assert_eq!(q.num_queues(), 3);
+        let caps = q.queues.iter_mut().map(|q| q.pq.get_mut().capacity()).collect::<Vec<_>>();
+        assert!(caps[0] >= 10 && caps[1] >= 100 && caps[2] >= 10);
+        assert!(caps[1] < 200);
+        assert_eq!(q.total_capacity(), caps.iter().sum::<usize>());
+    }
+
+    #[test]
+    fn pathological_queues() {
+        for &limit in &[usize::MAX, usize::MAX / 2, 1 << 50] {
+            let made = std::panic::catch_unwind(|| MilkPQ::<i32>::with_queues_unchecked(limit));
+            let msg = made.err().and_then(|err| err.downcast::<String>().ok()).unwrap();
+            assert_eq!(*msg, format!("can't allocate {limit} subqueues"));
+        }
+
+        assert_eq!(MilkPQ::<i32>::with_queues(usize::MAX).num_queues(), MAX_QUEUES);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one subqueue")]
+    fn with_capacities_empty() {
+        let _ = MilkPQ::<i32>::with_capacities(&[]);
+    }
+
+    #[test]
+    fn merge_sorted_into_vec() {
+        let a = (0..1000).filter(|t| t % 3 == 0).collect::<MilkPQ<_>>();
+        let b = (0..1000).filter(|t| t % 3 != 0).chain(500..600).collect::<MilkPQ<_>>();
+        let mut expected = (0..1000).chain(500..600).collect::<Vec<_>>();
+        expected.sort_unstable_by(|l, r| r.cmp(l));
+        assert_eq!(a.merge_sorted_into_vec(b), expected);
+        assert_eq!(MilkPQ::new().merge_sorted_into_vec((0..3).collect()), [2, 1, 0]);
+    }
+
+    #[test]
+    fn peek_max_approx() {
+        let q = MilkPQ::with_queues(8);
+        assert_eq!(q.peek_max_approx(), None);
+        q.extend_ref((0..1000).map(|i| i * 7 % 1000));
+        assert_eq!(q.peek_max_approx(), Some(999));
+        assert_eq!(q.len(), 1000);
+    }
+
+    #[test]
+    fn sample_push_pop() {
+        let mut q = MilkPQ::with_queues(4);
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut expected = SmallRng::seed_from_u64(7);
+
+        for t in 0..100 {
+            let i = q.sample_index(&mut expected);
+            q.sample_push(t, &mut rng);
+            assert_eq!(q.queues[i].pq.get_mut().peek(), Some(&t));
+        }
+
+        for _ in 0..100 {
+            let i = q.sample_index(&mut expected);
+            let top = q.queues[i].pq.get_mut().peek().copied();
+            assert_eq!(q.sample_pop(&mut rng), top);
+        }
+
+        // The same seed picks the same subqueues again.
+        let seeded = |seed| {
+            let q = MilkPQ::with_queues(4);
+            let mut rng = SmallRng::seed_from_u64(seed);
+            (0..100).for_each(|t| q.sample_push(t, &mut rng));
+            q.queues.iter().map(Queue::len_relaxed).collect::<Vec<_>>()
+        };
+        assert_eq!(seeded(7), seeded(7));
+    }
+
+    #[test]
+    fn track_len() {
+        for &track in &[true, false] {
+            let q = MilkPQ::with_queues(4).with_track_len(track);
+            q.extend_ref(0..100);
+            (0..100).for_each(|t| q.push(t));
+            assert_eq!(q.len(), 200);
+            assert!(q.strong_pop().is_some());
+            assert_eq!(q.len(), 199);
+            q.clear();
+            assert!(q.is_empty());
+            q.extend_from_slice(&[1, 2, 3]);
+            q.extend_ref(4..6);
+            assert_eq!(q.len(), 5);
+            assert_eq!(q.drain_concurrent().len(), 5);
+            q.extend_ref(0..10);
+            q.clear_and_shrink();
+            assert!(q.is_empty());
+            assert_eq!(q.len.load(Relaxed), 0);
+            q.extend_ref(0..199);
+            assert_eq!(q.len(), 199);
+            assert_eq!(q.len.load(Relaxed), if track { 199 } else { 0 });
+            assert_eq!(q.into_iter().len(), 199);
+        }
+
+        let mut q = MilkPQ::new().with_track_len(false);
         q.push(1);
+        assert!(q.pop().or_else(|| q.strong_pop()).is_some());
+        q = q.with_track_len(true);
+        assert!(q.is_empty());
         q.push(2);
-        assert!(q.strong_pop().is_some());
-        assert!(q.strong_pop().is_some());
-        assert!(q.strong_pop().is_none());
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.len.load(Relaxed), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "has to track its length")]
+    fn track_len_bounded() {
+        let _ = MilkPQ::<i32>::with_bound(10).with_track_len(false);
+    }
+
+    #[test]
+    fn into_sorted_vec_single_queue() {
+        let mut q = MilkPQ::with_queues(1);
+        q.extend_ref((0..1000).map(|t| t * 7 % 500));
+        let heap = q.queues[0].pq.get_mut();
+        let buf = heap.as_slice().as_ptr();
+        let mut expected = heap.clone().into_sorted_vec();
+        expected.reverse();
+        let sorted = q.into_sorted_vec();
+        assert_eq!(sorted, expected);
+        // Sorted in place, not copied out of the heap.
+        assert_eq!(sorted.as_ptr(), buf);
+    }
+
+    #[test]
+    fn into_sorted_vec_queues() {
+        for &limit in &[1, 3, 16, 64] {
+            let q = MilkPQ::with_queues(limit);
+            q.extend_ref((0..1000).map(|t| t * 7 % 500));
+            let mut expected = (0..1000).map(|t| t * 7 % 500).collect::<Vec<_>>();
+            expected.sort_unstable_by(|l, r| r.cmp(l));
+            assert_eq!(q.into_sorted_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn len_after_bulk_ops() {
+        fn check(q: &mut MilkPQ<i32>, len: usize) {
+            let actual = q.queues.iter_mut().map(|q| q.pq.get_mut().len()).sum::<usize>();
+            assert_eq!((q.len(), actual), (len, len));
+        }
+
+        let mut q = MilkPQ::with_queues(4);
+        q.extend_ref(0..100);
+        check(&mut q, 100);
+        assert_eq!(q.drain().len(), 100);
+        check(&mut q, 0);
+
+        q.extend_ref(0..100);
+        q.drain_into(&mut Vec::new());
+        check(&mut q, 0);
+
+        q.extend_ref(0..100);
+        q.clear();
+        check(&mut q, 0);
+
+        q.extend_ref(0..100);
+        q.clear_and_shrink();
+        check(&mut q, 0);
+
+        q.extend_ref(0..100);
+        assert_eq!(q.pop_all().len(), 100);
+        check(&mut q, 0);
+
+        q.extend_ref(0..100);
+        q.resize_queues(7);
+        check(&mut q, 100);
+        q.resize_queues(4);
+        check(&mut q, 100);
+
+        let mut q = q.merge((0..50).collect());
+        check(&mut q, 150);
+        let other = MilkPQ::with_queues(4);
+        other.extend_ref(0..30);
+        let mut q = q.merge(other);
+        check(&mut q, 180);
+
+        q.retain(|&t| t % 2 == 0);
+        let even = q.len();
+        check(&mut q, even);
+        q.drain_filter(|&t| t < 10);
+        let rest = q.len();
+        check(&mut q, rest);
+        q.heaps_mut().for_each(|mut heap| heap.clear());
+        check(&mut q, 0);
+    }
+
+    #[test]
+    fn resize_then_sample() {
+        let mut q = MilkPQ::with_queues(8);
+        q.extend_ref(0..100);
+
+        for &limit in &[2, 1, 16, 3, 1, 8] {
+            q.resize_queues(limit);
+            assert_eq!(q.num_queues(), limit);
+
+            for t in 0..1000 {
+                q.push(t);
+                q.pop();
+                q.pop_or_none(2);
+            }
+
+            q.extend_ref(0..100);
+            while q.strong_pop().is_some() {}
+        }
+
+        let mut small = MilkPQ::with_queues(2);
+        small.clone_from(&MilkPQ::from_one_with_queues(1, 32));
+        (0..1000).for_each(|t| small.push(t));
+        assert_eq!(small.num_queues(), 32);
+        assert_eq!(small.len(), 1001);
+    }
+
+    #[test]
+    fn with_seed_and_queues() {
+        let run = |seed| {
+            let q = MilkPQ::with_seed_and_queues(seed, 8);
+            (0..200).for_each(|t| q.push(t));
+            q.extend_ref(200..300);
+            let popped = (0..100).map(|_| q.pop()).collect::<Vec<_>>();
+            let mut q = q;
+            let lens = q.queues.iter_mut().map(|q| q.pq.get_mut().len()).collect::<Vec<_>>();
+            (popped, lens)
+        };
+
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+
+        // A clone starts over from the seed.
+        let mut a = MilkPQ::with_seed_and_queues(3, 8);
+        (0..100).for_each(|t| a.push(t));
+        let mut b = a.clone();
+        b.clear();
+        (0..100).for_each(|t| b.push(t));
+        let lens = |q: &mut MilkPQ<i32>| q.queues.iter_mut().map(|q| q.pq.get_mut().len()).collect::<Vec<_>>();
+        assert_eq!(lens(&mut a), lens(&mut b));
+    }
+
+    #[test]
+    fn from_iter_streaming() {
+        let n = 3_000_000_u64;
+        // Lazy, and the filter only bounds its size hint from above.
+        let lazy = (0..n).filter(|t| t % 7 != 3);
+        let mut q = lazy.clone().collect::<MilkPQ<_>>();
+        let count = lazy.clone().count();
+        assert_eq!(q.len(), count);
+
+        let lens = q.queues.iter_mut().map(|q| q.pq.get_mut().len()).collect::<Vec<_>>();
+        assert!(lens.iter().max().unwrap() - lens.iter().min().unwrap() <= 1);
+        assert!(q.queues.iter_mut().all(|q| {
+            let heap = q.pq.get_mut().as_slice();
+            (1..heap.len()).all(|i| heap[(i - 1) / 2] >= heap[i])
+        }));
+        assert_eq!(q.peek_max_approx(), Some(n - 1));
+        assert_eq!(q.into_iter().map(|t| t % 7).filter(|&r| r == 3).count(), 0);
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn counting_allocator() {
+        use std::alloc::{AllocError, Layout};
+        use std::ptr::NonNull;
+
+        /// Counts the blocks it hands out, and how many of them are live.
+        #[derive(Default)]
+        struct Counting {
+            allocs: AtomicUsize,
+            live: AtomicUsize,
+        }
+
+        unsafe impl Allocator for &Counting {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                self.allocs.fetch_add(1, Relaxed);
+                self.live.fetch_add(1, Relaxed);
+                Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.live.fetch_sub(1, Relaxed);
+                Global.deallocate(ptr, layout);
+            }
+        }
+
+        let counting = Counting::default();
+        let live = || counting.live.load(Relaxed);
+        let mut q = MilkPQ::with_queues_in(4, &counting);
+        assert_eq!(counting.allocs.load(Relaxed), 0);
+
+        // One buffer per subqueue, since `extend_ref` deals to all of them.
+        q.extend_ref(0..1000);
+        assert_eq!(live(), 4);
+        q.resize_queues(7);
+        assert_eq!(live(), 7);
+        q.update_each(|t| *t += 1);
+        q.drain_filter(|t| t % 2 == 0);
+        assert_eq!(live(), 7);
+
+        let clone = q.clone();
+        assert_eq!(live(), 14);
+        drop(clone);
+        assert_eq!(live(), 7);
+
+        assert_eq!(q.drain_sorted(), (1..1000).rev().step_by(2).collect::<Vec<_>>());
+        q.clear_and_shrink();
+        assert_eq!(live(), 0);
+        assert!(counting.allocs.load(Relaxed) > 14);
     }
 }
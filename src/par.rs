@@ -0,0 +1,177 @@
+//! [`rayon`] integration.
+
+use rayon::prelude::*;
+use super::MilkPQ;
+
+impl<T: Ord + Send, S> IntoParallelIterator for MilkPQ<T, S> {
+    type Item = T;
+    type Iter = rayon::iter::Flatten<rayon::vec::IntoIter<Vec<T>>>;
+
+    fn into_par_iter(self) -> Self::Iter {
+        self.queues
+            .into_vec()
+            .into_iter()
+            .map(|queue| queue.pq.into_inner().into_vec())
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .flatten()
+    }
+}
+
+impl<T: Ord + Send, S: Sync> ParallelExtend<T> for MilkPQ<T, S> {
+    fn par_extend<I: IntoParallelIterator<Item = T>>(&mut self, par_iter: I) {
+        self.par_extend_ref(par_iter);
+    }
+}
+
+impl<T: Ord + Send, S: Sync> MilkPQ<T, S> {
+    /// Extend `self` using a [`IntoParallelIterator`], pushing from rayon's
+    /// threads.
+    ///
+    /// Exactly like [`ParallelExtend`], except it doesn't mutably borrow
+    /// `self`.
+    pub fn par_extend_ref<I: IntoParallelIterator<Item = T>>(&self, par_iter: I) {
+        par_iter.into_par_iter().for_each(|t| self.push(t));
+    }
+}
+
+impl<T: Ord + Clone + Send + Sync, S: Clone> MilkPQ<T, S> {
+    /// Clone `self`, cloning the subqueues in parallel.
+    ///
+    /// Each subqueue is only locked while it's being cloned, just like with
+    /// [`Clone`], so this is the same snapshot but spread across threads.
+    #[must_use]
+    pub fn clone_par(&self) -> Self {
+        let queues = self.queues
+            .par_iter()
+            .map(Clone::clone)
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+
+        self.with_cloned_queues(queues)
+    }
+}
+
+impl<T: Ord + Send, S> MilkPQ<T, S> {
+    /// Like [`retain()`](MilkPQ::retain()), but goes through the subqueues in
+    /// parallel.
+    pub fn retain_par<F: Fn(&T) -> bool + Sync>(&mut self, f: F) {
+        self.queues.par_iter_mut().for_each(|queue| queue.retain(&f));
+        self.recount();
+    }
+
+    /// Like [`drain_filter()`](MilkPQ::drain_filter()), but goes through the
+    /// subqueues in parallel.
+    pub fn drain_filter_par<F: Fn(&T) -> bool + Sync>(&mut self, f: F) -> Vec<T> {
+        let removed = self.queues
+            .par_iter_mut()
+            .flat_map_iter(|queue| queue.drain_filter(&f))
+            .collect();
+        self.recount();
+        removed
+    }
+
+    /// Like [`drain()`](MilkPQ::drain()), but empties the subqueues in
+    /// parallel.
+    ///
+    /// Each subqueue keeps its capacity, as with [`drain()`](MilkPQ::drain()).
+    /// The elements come out grouped by subqueue, in no particular order.
+    pub fn drain_par(&mut self) -> Vec<T> {
+        let runs = self.queues
+            .par_iter_mut()
+            .map(|queue| queue.take().into_vec())
+            .collect::<Vec<_>>();
+        let mut vec = Vec::with_capacity(runs.iter().map(Vec::len).sum());
+
+        for mut run in runs {
+            vec.append(&mut run);
+        }
+
+        *self.len.get_mut() = 0;
+        vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_par() {
+        let mut q = MilkPQ::with_queues(16);
+        q.extend_ref((0..10_000).map(|t| t % 1000));
+        let mut drained = q.drain_par();
+        drained.sort_unstable();
+        let mut expected = (0..10_000).map(|t| t % 1000).collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(drained, expected);
+        assert!(q.is_empty());
+        assert_eq!(q.strong_pop(), None);
+
+        // Owned elements are moved out exactly once.
+        let mut q = (0..1000).map(|t| t.to_string()).collect::<MilkPQ<_>>();
+        let mut drained = q.drain_par();
+        drained.sort_unstable_by_key(|t| t.parse::<u32>().unwrap());
+        assert_eq!(drained, (0..1000).map(|t| t.to_string()).collect::<Vec<_>>());
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn into_par_iter() {
+        let q = (0..1000u64).collect::<MilkPQ<_>>();
+        let seq = q.clone().into_iter().sum::<u64>();
+        assert_eq!(q.into_par_iter().sum::<u64>(), seq);
+    }
+
+    #[test]
+    fn par_extend() {
+        let mut q = MilkPQ::new();
+        q.par_extend((0..1000).into_par_iter());
+        q.par_extend_ref((1000..2000).into_par_iter());
+        assert_eq!(q.len(), 2000);
+        assert_eq!(q.into_sorted_vec(), (0..2000).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn par_extend_send_only() {
+        use std::cell::Cell;
+
+        // `Cell` is `Send` but not `Sync`, which is all pushing needs.
+        let mut q = MilkPQ::new();
+        q.par_extend((0..1000).into_par_iter().map(Cell::new));
+        q.par_extend_ref((1000..2000).into_par_iter().map(Cell::new));
+        assert_eq!(q.len(), 2000);
+        let sorted = q.into_sorted_vec().into_iter().map(Cell::into_inner).collect::<Vec<_>>();
+        assert_eq!(sorted, (0..2000).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn clone_par() {
+        let q = (0..1000).collect::<MilkPQ<_>>();
+        let c = q.clone_par();
+        assert_eq!(c.len(), 1000);
+        assert_eq!(c.into_sorted_vec(), q.into_sorted_vec());
+    }
+
+    #[test]
+    fn retain_par() {
+        let mut seq = (0..1000).collect::<MilkPQ<_>>();
+        let mut par = seq.clone();
+        seq.retain(|&t| t % 3 == 0);
+        par.retain_par(|&t| t % 3 == 0);
+        assert_eq!(par.len(), seq.len());
+        assert_eq!(par.into_sorted_vec(), seq.into_sorted_vec());
+    }
+
+    #[test]
+    fn drain_filter_par() {
+        let mut seq = (0..1000).collect::<MilkPQ<_>>();
+        let mut par = seq.clone();
+        let mut seq_removed = seq.drain_filter(|&t| t % 3 == 0);
+        let mut par_removed = par.drain_filter_par(|&t| t % 3 == 0);
+        seq_removed.sort_unstable();
+        par_removed.sort_unstable();
+        assert_eq!(par_removed, seq_removed);
+        assert_eq!(par.into_sorted_vec(), seq.into_sorted_vec());
+    }
+}
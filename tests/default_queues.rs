@@ -0,0 +1,17 @@
+//! `set_default_queues()` changes a process-wide setting, so it's tested in
+//! its own binary, where no other test can build a queue in the meantime.
+
+use milkpq::{default_queues, set_default_queues, MilkPQ, DEFAULT_OVERSUBSCRIPTION};
+
+#[test]
+fn set_default_queues_everywhere() {
+    set_default_queues(3);
+    assert_eq!(default_queues(), 3);
+    assert_eq!(MilkPQ::<i32>::new().num_queues(), 3);
+    assert_eq!(MilkPQ::<i32>::default().num_queues(), 3);
+    assert_eq!((0..10).collect::<MilkPQ<_>>().num_queues(), 3);
+    set_default_queues(0);
+    assert_eq!(default_queues(), num_cpus::get() * DEFAULT_OVERSUBSCRIPTION);
+    assert_eq!(MilkPQ::<i32>::new().num_queues(), MilkPQ::<i32>::recommended_queues());
+    assert_eq!(MilkPQ::<i32>::recommended_queues(), num_cpus::get() * DEFAULT_OVERSUBSCRIPTION);
+}